@@ -1,4 +1,18 @@
+use errors::GenericError;
+
 pub enum FileStreamMessage {
     Start(u32),
-    Finish(u32)
+    Finish(u32),
+    // A problem that did not stop the file from being written.
+    Warning(u32, String),
+    // A task of the transfer failed, which ends the whole transfer.
+    Failed(GenericError)
+}
+
+// Transfer tasks run with `ThreadPool::execute_forwarding`, which hands
+// their errors to the loop that waits on the transfer this way.
+impl From<GenericError> for FileStreamMessage {
+    fn from(err: GenericError) -> Self {
+        FileStreamMessage::Failed(err)
+    }
 }
@@ -0,0 +1,244 @@
+use std::{collections::HashMap, io::{stdin, stdout, Write}, net::TcpStream};
+
+use errors::GenericError;
+use files::{EntryKind, FileEntry};
+use net::JSONReader;
+
+use crate::{logger::format_bytes, messages::{DSMessage, DSMessageType, ListingEntry, MessageListing}};
+
+static HELP: &str = "cd <dir>, get <file or glob>, ls, done";
+
+// One level of the sender's tree, below `dir`.
+pub fn list_dir(files: &[FileEntry], dir: &[String]) -> Vec<ListingEntry> {
+    let mut entries = Vec::<ListingEntry>::new();
+    let mut index = HashMap::<&String, usize>::new();
+
+    for f in files.iter() {
+        if f.partial_path.len() <= dir.len() || !f.partial_path.starts_with(dir) {
+            continue;
+        }
+
+        let name = &f.partial_path[dir.len()];
+        let i = match index.get(name) {
+            Some(i) => *i,
+            None => {
+                entries.push(ListingEntry {
+                    name: name.clone(),
+                    kind: EntryKind::Dir,
+                    size: 0,
+                    files: 0
+                });
+                index.insert(name, entries.len() - 1);
+                entries.len() - 1
+            }
+        };
+
+        let entry = &mut entries[i];
+        if f.partial_path.len() == dir.len() + 1 {
+            entry.kind = f.kind.clone();
+            if f.kind != EntryKind::Dir {
+                entry.size = f.size;
+            }
+        }
+        else if f.kind != EntryKind::Dir {
+            entry.size += f.size;
+            entry.files += 1;
+        }
+    }
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries
+}
+
+fn display_name(name: &str) -> String {
+    files::decode_name(name).to_string_lossy().into_owned()
+}
+
+// Names are taken literally when they become part of a pattern.
+fn escape_pattern(name: &str) -> String {
+    let mut res = String::new();
+    for c in name.chars() {
+        if "\\*?[]!#".contains(c) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+fn list_remote(stream: &mut TcpStream, reader: &mut JSONReader, dir: &[String]) -> Result<Vec<ListingEntry>, GenericError> {
+    let message = DSMessage {
+        message_type: DSMessageType::ListDir(dir.to_vec())
+    };
+    let json = serde_json::to_string(&message)?;
+    stream.write_all(json.as_bytes())?;
+
+    let listing: MessageListing = serde_json::from_value(reader.read_json()?)?;
+    Ok(listing.entries)
+}
+
+fn print_listing(entries: &[ListingEntry]) {
+    for e in entries.iter() {
+        let name = display_name(&e.name);
+        match &e.kind {
+            EntryKind::Dir => println!("  {}/  {} files, {}", name, e.files, format_bytes(e.size)),
+            EntryKind::Symlink(target) => println!("  {} -> {}", name, display_name(target)),
+            _ => println!("  {}  {}", name, format_bytes(e.size))
+        }
+    }
+}
+
+// Walks the sender's tree one directory at a time and returns the
+// patterns the user picked, anchored at the root of the tree.
+pub fn browse(stream: &mut TcpStream, reader: &mut JSONReader) -> Result<Vec<String>, GenericError> {
+    let mut dir: Vec<String> = vec![];
+    let mut picked = vec![];
+
+    let _prompt = crate::PROMPT.lock().unwrap();
+    let mut listing = list_remote(stream, reader, &dir)?;
+    println!("{}", HELP);
+    print_listing(&listing);
+
+    loop {
+        let cur: Vec<String> = dir.iter()
+            .map(|name| display_name(name))
+            .collect();
+        print!("/{}> ", cur.join("/"));
+        stdout().flush()?;
+
+        let mut buf = String::new();
+        if stdin().read_line(&mut buf)? == 0 {
+            break;
+        }
+        let line = buf.trim();
+        let (command, arg) = match line.split_once(' ') {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, "")
+        };
+
+        match command {
+            "ls" => {
+                print_listing(&listing);
+            }
+            "cd" => {
+                match arg {
+                    "" | "/" => {
+                        dir.clear();
+                    }
+                    ".." => {
+                        dir.pop();
+                    }
+                    name => {
+                        let entry = listing.iter()
+                            .find(|e| e.kind == EntryKind::Dir && display_name(&e.name) == name);
+                        match entry {
+                            Some(e) => {
+                                dir.push(e.name.clone());
+                            }
+                            None => {
+                                println!("no directory {}", name);
+                                continue;
+                            }
+                        }
+                    }
+                }
+                listing = list_remote(stream, reader, &dir)?;
+                print_listing(&listing);
+            }
+            "get" if !arg.is_empty() => {
+                let mut pattern: Vec<String> = cur.iter()
+                    .map(|name| escape_pattern(name))
+                    .collect();
+                pattern.push(arg.to_owned());
+                let pattern = format!("/{}", pattern.join("/"));
+                println!("picked {}", pattern);
+                picked.push(pattern);
+            }
+            "done" => {
+                break;
+            }
+            _ => {
+                println!("{}", HELP);
+            }
+        }
+    }
+
+    Ok(picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use files::{EntryKind, FileEntry};
+
+    use super::{escape_pattern, list_dir};
+
+    fn entry(path: &str, kind: EntryKind, size: u64) -> FileEntry {
+        FileEntry {
+            partial_path: path.split('/').map(|n| n.to_string()).collect(),
+            kind,
+            size,
+            mode: 0o644,
+            mtime: 0,
+            atime: 0,
+            xattrs: None
+        }
+    }
+
+    fn listing(files: &[FileEntry], dir: &[&str]) -> Vec<(String, EntryKind, u64, u64)> {
+        let dir: Vec<String> = dir.iter().map(|n| n.to_string()).collect();
+        list_dir(files, &dir).into_iter()
+            .map(|e| (e.name, e.kind, e.size, e.files))
+            .collect()
+    }
+
+    #[test]
+    fn dirs_count_the_files_below_them() {
+        let files = vec![
+            entry("d", EntryKind::Dir, 0),
+            entry("d/a", EntryKind::File, 10),
+            entry("d/sub", EntryKind::Dir, 0),
+            entry("d/sub/b", EntryKind::File, 20),
+            entry("d/sub/c", EntryKind::File, 30),
+            entry("d/sub/deeper", EntryKind::Dir, 0),
+            entry("d/sub/deeper/e", EntryKind::File, 5),
+            entry("d/sub/l", EntryKind::Symlink("b".to_string()), 0),
+            entry("top", EntryKind::File, 7)
+        ];
+
+        assert_eq!(listing(&files, &[]), [
+            ("d".to_string(), EntryKind::Dir, 65, 5),
+            ("top".to_string(), EntryKind::File, 7, 0)
+        ]);
+        assert_eq!(listing(&files, &["d"]), [
+            ("a".to_string(), EntryKind::File, 10, 0),
+            ("sub".to_string(), EntryKind::Dir, 55, 4)
+        ]);
+        assert_eq!(listing(&files, &["d", "sub", "deeper"]), [
+            ("e".to_string(), EntryKind::File, 5, 0)
+        ]);
+        assert!(listing(&files, &["missing"]).is_empty());
+    }
+
+    #[test]
+    fn escaped_names_match_literally() {
+        assert_eq!(escape_pattern("a*b"), "a\\*b");
+        assert_eq!(escape_pattern("[x]"), "\\[x\\]");
+        assert_eq!(escape_pattern("!x"), "\\!x");
+
+        let files = vec![
+            entry("a*b", EntryKind::File, 1),
+            entry("acb", EntryKind::File, 1),
+            entry("[x]", EntryKind::File, 1),
+            entry("x", EntryKind::File, 1),
+            entry("!x", EntryKind::File, 1)
+        ];
+        for name in ["a*b", "[x]", "!x"] {
+            let pattern = format!("/{}", escape_pattern(name));
+            let selected = files::select_files(Path::new("/root"), &files, &[pattern]).unwrap();
+            let selected: Vec<String> = selected.iter().map(|f| f.partial_path.join("/")).collect();
+            assert_eq!(selected, [name]);
+        }
+    }
+}
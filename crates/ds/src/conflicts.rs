@@ -0,0 +1,124 @@
+use std::{io::{stdin, stdout, Write}, path::PathBuf};
+
+use errors::{new_custom_error, GenericError};
+use files::{DestDir, FileEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    Overwrite,
+    Skip,
+    Newer,
+    Rename,
+    Ask
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = GenericError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "newer" => Ok(ConflictPolicy::Newer),
+            "rename" => Ok(ConflictPolicy::Rename),
+            "ask" => Ok(ConflictPolicy::Ask),
+            _ => Err(new_custom_error("--conflict must be overwrite, skip, newer, rename or ask"))
+        }
+    }
+}
+
+pub enum Resolution {
+    Overwrite,
+    Keep,
+    // The incoming file is written under this path instead.
+    Rename(Vec<String>)
+}
+
+pub struct Conflict {
+    pub path: PathBuf,
+    pub resolution: Resolution
+}
+
+// The first `<name>.ds-conflict`, `<name>.ds-conflict-2`, ... that is free.
+fn rename_target(dest: &DestDir, partial_path: &[String]) -> Vec<String> {
+    let mut path = partial_path.to_vec();
+    let name = partial_path.last().cloned().unwrap_or_default();
+
+    let mut i = 1;
+    loop {
+        let suffix = match i {
+            1 => ".ds-conflict".to_owned(),
+            _ => format!(".ds-conflict-{}", i)
+        };
+        if let Some(last) = path.last_mut() {
+            *last = format!("{}{}", name, suffix);
+        }
+        if dest.metadata(&path).is_err() {
+            return path;
+        }
+        i += 1;
+    }
+}
+
+fn ask(dest: &DestDir, file: &FileEntry) -> Result<Resolution, GenericError> {
+    let _prompt = crate::PROMPT.lock().unwrap();
+    print!(
+        "{} already exists. (o)verwrite, (s)kip or (r)ename the incoming file? ",
+        files::list_to_path(&file.partial_path).display());
+    stdout().flush()?;
+
+    let mut buf = String::new();
+    stdin().read_line(&mut buf)?;
+    let resolution = match buf.trim().to_lowercase().as_str() {
+        "o" => Resolution::Overwrite,
+        "r" => Resolution::Rename(rename_target(dest, &file.partial_path)),
+        _ => Resolution::Keep
+    };
+
+    Ok(resolution)
+}
+
+// Decides what happens to an incoming file whose path is already taken.
+// Returns None if there is nothing in the way.
+pub fn resolve(dest: &DestDir, file: &FileEntry, policy: ConflictPolicy) -> Result<Option<Resolution>, GenericError> {
+    let meta = match dest.metadata(&file.partial_path) {
+        Ok(meta) if !meta.is_dir() => meta,
+        _ => {
+            return Ok(None);
+        }
+    };
+
+    let resolution = match policy {
+        ConflictPolicy::Overwrite => Resolution::Overwrite,
+        ConflictPolicy::Skip => Resolution::Keep,
+        ConflictPolicy::Newer => {
+            let local = files::to_timestamp(meta.modified()?);
+            match local >= file.mtime {
+                true => Resolution::Keep,
+                false => Resolution::Overwrite
+            }
+        }
+        ConflictPolicy::Rename => Resolution::Rename(rename_target(dest, &file.partial_path)),
+        ConflictPolicy::Ask => ask(dest, file)?
+    };
+
+    Ok(Some(resolution))
+}
+
+pub fn print_summary(conflicts: &[Conflict]) {
+    if conflicts.is_empty() {
+        return;
+    }
+
+    println!("{} files already existed:", conflicts.len());
+    for c in conflicts.iter() {
+        match &c.resolution {
+            Resolution::Overwrite => println!("  {} - overwritten", c.path.display()),
+            Resolution::Keep => println!("  {} - kept", c.path.display()),
+            Resolution::Rename(path) => println!(
+                "  {} - received as {}",
+                c.path.display(),
+                files::list_to_path(path).display())
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use crate::{logger::format_bytes, mirror::Deletions};
+
+pub enum Action {
+    Create(u64),
+    Overwrite(u64),
+    Skip(u64),
+    // An existing file kept by the conflict policy.
+    Keep,
+    CreateDir,
+    Link(PathBuf)
+}
+
+// What a transfer would do, collected by `receive_files` in dry-run mode.
+pub struct Plan {
+    pub actions: Vec<(PathBuf, Action)>,
+    pub deletions: Option<Deletions>,
+    pub max_delete: usize
+}
+
+pub fn print_plan(plan: &Plan) {
+    let mut created = (0, 0);
+    let mut overwritten = (0, 0);
+    let mut skipped = (0, 0);
+    let mut kept = 0;
+    let mut dirs = 0;
+    let mut links = 0;
+
+    for (path, action) in plan.actions.iter() {
+        let path = path.display();
+        match action {
+            Action::Create(size) => {
+                println!("create {} ({})", path, format_bytes(*size));
+                created = (created.0 + 1, created.1 + size);
+            }
+            Action::Overwrite(size) => {
+                println!("overwrite {} ({})", path, format_bytes(*size));
+                overwritten = (overwritten.0 + 1, overwritten.1 + size);
+            }
+            Action::Skip(size) => {
+                println!("skip {} (unchanged)", path);
+                skipped = (skipped.0 + 1, skipped.1 + size);
+            }
+            Action::Keep => {
+                println!("keep {} (already exists)", path);
+                kept += 1;
+            }
+            Action::CreateDir => {
+                println!("create {}/", path);
+                dirs += 1;
+            }
+            Action::Link(target) => {
+                println!("link {} to {}", path, target.display());
+                links += 1;
+            }
+        }
+    }
+
+    let mut deleted = 0;
+    if let Some(deletions) = &plan.deletions {
+        for path in deletions.files.iter() {
+            println!("delete {}", path.display());
+        }
+        for path in deletions.dirs.iter() {
+            println!("delete {}/", path.display());
+        }
+        deleted = deletions.files.len() + deletions.dirs.len();
+    }
+
+    println!();
+    println!("{} files to create ({})", created.0, format_bytes(created.1));
+    println!("{} files to overwrite ({})", overwritten.0, format_bytes(overwritten.1));
+    println!("{} files to skip ({})", skipped.0, format_bytes(skipped.1));
+    if kept > 0 {
+        println!("{} existing files to keep", kept);
+    }
+    if dirs > 0 {
+        println!("{} directories to create", dirs);
+    }
+    if links > 0 {
+        println!("{} hard links to create", links);
+    }
+    if plan.deletions.is_some() {
+        println!("{} entries to delete", deleted);
+        // The real run checks the limit before it transfers anything.
+        if deleted > plan.max_delete {
+            println!(
+                "the transfer would stop: {} deletions exceed the limit of {} (see --max-delete)",
+                deleted,
+                plan.max_delete);
+        }
+    }
+    println!("{} to transfer", format_bytes(created.1 + overwritten.1));
+}
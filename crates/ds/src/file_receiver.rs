@@ -1,12 +1,12 @@
-use std::{io::{Read, Write}, net::TcpStream, path::PathBuf, sync::{mpsc::{channel, Sender}, Arc, Mutex}};
+use std::{collections::HashMap, io::{Read, Write}, net::TcpStream, os::unix::fs::{MetadataExt, PermissionsExt}, path::PathBuf, sync::{mpsc::{channel, Sender}, Arc}};
 
 use common::FileStreamMessage;
 use errors::{new_custom_error, GenericError};
-use files::{FileChunk, FileWriter, FILE_CHUNK_MAX_SIZE};
+use files::{DestDir, EntryKind, FileChunk, FileEntry, FileWriter, WriteMode, FILE_CHUNK_HEADER_SIZE, FILE_CHUNK_MAX_SIZE};
 use net::{JSONReader, TcpEndpoint};
 use thread_pool::ThreadPool;
 
-use crate::{logger::LoggerMessage, messages::{DSMessage, DSMessageType, DownloadFile, MessageFiles}};
+use crate::{browse, conflicts::{self, Conflict, ConflictPolicy, Resolution}, dry_run::{self, Action, Plan}, logger::LoggerMessage, mirror, staging, messages::{DSMessage, DSMessageType, DownloadFile, FileDelta, FilePrefix, MessageFiles, MessagePrefixes}, options::Options, sync, watch};
 
 enum ReadResult {
     StreamClosed,
@@ -40,12 +40,9 @@ fn read_bytes(stream: &mut TcpStream, buf: &mut [u8]) -> ReadResult {
 fn receive_chunk(stream: &mut TcpStream) ->
     Result<Option<FileChunk>, GenericError> {
 
-    let mut buf: Vec<u8> = Vec::with_capacity(FILE_CHUNK_MAX_SIZE);
-    buf.resize(FILE_CHUNK_MAX_SIZE, 0);
-
+    let mut header: Vec<u8> = vec![0; FILE_CHUNK_HEADER_SIZE];
     {
-        let meta_data = &mut buf[..2 * size_of::<u64>()];
-        let read = read_bytes(stream, meta_data);
+        let read = read_bytes(stream, &mut header);
         match read {
             ReadResult::StreamClosed => {
                 return Ok(None);
@@ -56,14 +53,16 @@ fn receive_chunk(stream: &mut TcpStream) ->
             _ => { }
         }
     }
-    let mut data_size = [0; size_of::<u64>()];
-    data_size.copy_from_slice(&buf[size_of::<u64>()..2 * size_of::<u64>()]);
-    let data_size = u64::from_be_bytes(data_size);
+    let data_size = FileChunk::data_size_from_header(&header);
+    if data_size as usize > FILE_CHUNK_MAX_SIZE - FILE_CHUNK_HEADER_SIZE {
+        return Err(new_custom_error("chunk too large"));
+    }
 
-    let res = {
-        let data_range = 2 * size_of::<u64>()..2 * size_of::<u64>() + data_size as usize;
-        read_bytes(stream, &mut buf[data_range])
-    };
+    // Block references carry no data, so only allocate what the chunk needs.
+    let mut buf: Vec<u8> = vec![0; FILE_CHUNK_HEADER_SIZE + data_size as usize];
+    buf[..FILE_CHUNK_HEADER_SIZE].copy_from_slice(&header);
+
+    let res = read_bytes(stream, &mut buf[FILE_CHUNK_HEADER_SIZE..]);
     match res {
         ReadResult::FullyRead => {
             let chunk = FileChunk::from_bytes(&buf);
@@ -75,48 +74,538 @@ fn receive_chunk(stream: &mut TcpStream) ->
     }
 }
 
-pub fn receive_files(
-    mut tcp_endpoint: impl TcpEndpoint,
+// With `exact` set, the permissions and extended attributes have to match
+// as well, for files that must not be modified where they are.
+fn is_unchanged(dest: &DestDir, file: &FileEntry, exact: bool) -> bool {
+    if let EntryKind::Symlink(target) = &file.kind {
+        return match dest.read_link(&file.partial_path) {
+            Ok(current) => current.as_os_str() == files::decode_name(target),
+            Err(_) => false
+        };
+    }
+
+    let meta = match dest.metadata(&file.partial_path) {
+        Ok(meta) => meta,
+        Err(_) => {
+            return false;
+        }
+    };
+    if !meta.is_file() || meta.len() != file.size {
+        return false;
+    }
+
+    if exact {
+        if meta.permissions().mode() & 0o7777 != file.mode {
+            return false;
+        }
+        if let Some(xattrs) = &file.xattrs {
+            match dest.with_path(&file.partial_path, files::read_xattrs) {
+                Ok(current) if files::xattrs_match(&current, xattrs) => { }
+                _ => {
+                    return false;
+                }
+            }
+        }
+    }
+
+    match meta.modified() {
+        Ok(modified) => files::to_timestamp(modified) == file.mtime,
+        Err(_) => false
+    }
+}
+
+fn update_permissions(dest: &DestDir, file: &FileEntry) -> Result<(), GenericError> {
+    let meta = dest.metadata(&file.partial_path)?;
+    if meta.permissions().mode() & 0o7777 != file.mode {
+        dest.set_mode(&file.partial_path, file.mode)?;
+    }
+
+    Ok(())
+}
+
+fn create_symlink(dest: &DestDir, file: &FileEntry, target: &str) -> Result<(), GenericError> {
+    if let Ok(meta) = dest.metadata(&file.partial_path) {
+        if meta.is_dir() {
+            return Err(new_custom_error("directory in place of a symbolic link"));
+        }
+        dest.remove_file(&file.partial_path)?;
+    }
+
+    dest.symlink(&files::decode_name(target), &file.partial_path)
+}
+
+fn is_linked(dest: &DestDir, partial_path: &[String], target: &[String]) -> bool {
+    match (dest.metadata(partial_path), dest.metadata(target)) {
+        (Ok(meta), Ok(target_meta)) => meta.dev() == target_meta.dev() && meta.ino() == target_meta.ino(),
+        _ => false
+    }
+}
+
+// The path an entry ends up at once the directories it is in are renamed.
+fn move_below(partial_path: &[String], renamed_dirs: &[(Vec<String>, Vec<String>)]) -> Vec<String> {
+    for (from, to) in renamed_dirs.iter() {
+        if partial_path.len() > from.len() && partial_path.starts_with(from) {
+            let mut path = to.clone();
+            path.extend_from_slice(&partial_path[from.len()..]);
+            return path;
+        }
+    }
+
+    partial_path.to_vec()
+}
+
+// Returns false if the entry already was a link to `target`.
+fn create_hard_link(dest: &DestDir, partial_path: &[String], target: &[String]) -> Result<bool, GenericError> {
+    let target_meta = dest.metadata(target)?;
+
+    if let Ok(meta) = dest.metadata(partial_path) {
+        if meta.dev() == target_meta.dev() && meta.ino() == target_meta.ino() {
+            return Ok(false);
+        }
+        if meta.is_dir() {
+            return Err(new_custom_error("directory in place of a hard link"));
+        }
+        dest.remove_file(partial_path)?;
+    }
+
+    dest.hard_link(target, partial_path)?;
+    Ok(true)
+}
+
+// Extended attributes are best effort, failures are only reported.
+fn apply_xattrs(dest: &DestDir, file: &FileEntry, logger: &Sender<LoggerMessage>) -> Result<(), GenericError> {
+    if let Some(xattrs) = &file.xattrs {
+        for failure in dest.with_path(&file.partial_path, |path| files::apply_xattrs(path, xattrs))? {
+            logger.send(LoggerMessage::Warning { message: failure })?;
+        }
+    }
+
+    Ok(())
+}
+
+// Nothing is written unless every path in the list stays inside the
+// destination and leaves the sync state alone.
+pub fn check_file_list(files: &[FileEntry]) -> Result<(), GenericError> {
+    for f in files.iter() {
+        files::check_partial_path(&f.partial_path)?;
+        if sync::is_state_file(&f.partial_path) {
+            return Err(new_custom_error("the file list contains a sync state file"));
+        }
+        if let EntryKind::HardLink(target) = &f.kind {
+            files::check_partial_path(target)?;
+            if sync::is_state_file(target) {
+                return Err(new_custom_error("the file list contains a sync state file"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Interrupted transfers leave their data in the temporary file, unless it
+// was written in place.
+fn partial_prefix(dest: &DestDir, id: u32, file: &FileEntry, inplace: bool) -> Option<FilePrefix> {
+    let path = match inplace {
+        true => file.partial_path.clone(),
+        false => files::temp_path(&file.partial_path).ok()?
+    };
+    let f = dest.open_file(&path).ok()?;
+    let meta = f.metadata().ok()?;
+    if !meta.is_file() || meta.len() == 0 || meta.len() >= file.size {
+        return None;
+    }
+
+    let size = meta.len();
+    let hash = files::hash_file_prefix(f, size).ok()?;
+    Some(FilePrefix {
+        id,
+        size,
+        hash
+    })
+}
+
+// Receives one transfer into `root`, and the changes that follow it if
+// the sender watches its tree. The endpoint is handed back at the end,
+// so the same connection can be used again.
+pub fn receive_files<E: TcpEndpoint>(
+    tcp_endpoint: E,
     root: PathBuf,
-    logger: Sender<LoggerMessage>) -> Result<(), GenericError> {
+    options: Options,
+    logger: Sender<LoggerMessage>) -> Result<E, GenericError> {
+
+    let mut options = options;
+    let (tcp_endpoint, watch) = receive_transfer(tcp_endpoint, root.clone(), &mut options, logger.clone())?;
+    match watch {
+        true => watch::receive_changes(tcp_endpoint, root, options, logger),
+        false => Ok(tcp_endpoint)
+    }
+}
+
+// Also tells whether the sender keeps the session open. Files picked
+// while browsing are added to `options`, so later transfers of the same
+// session get the same selection.
+fn receive_transfer<E: TcpEndpoint>(
+    mut tcp_endpoint: E,
+    root: PathBuf,
+    options: &mut Options,
+    logger: Sender<LoggerMessage>) -> Result<(E, bool), GenericError> {
 
     let mut stream = tcp_endpoint.get_connection()?;
+    let mut reader = JSONReader::new(stream.try_clone()?);
+
+    if options.browse {
+        let picked = browse::browse(&mut stream, &mut reader)?;
+        options.pick.extend(picked);
+        options.browse = false;
+    }
+    if !options.pick.is_empty() {
+        let message = DSMessage {
+            message_type: DSMessageType::SelectFiles(options.pick.clone())
+        };
+        let json = serde_json::to_string(&message)?;
+        stream.write_all(json.as_bytes())?;
+    }
+
     let message = DSMessage {
         message_type: DSMessageType::GetFileList
     };
     let json = serde_json::to_string(&message)?;
 
-    stream.write(json.as_bytes())?;
+    stream.write_all(json.as_bytes())?;
 
-    let mut reader = JSONReader::new(stream);
     let files = reader.read_json()?;
-    let files: MessageFiles = serde_json::from_value(files)?;
+    let mut files: MessageFiles = serde_json::from_value(files)?;
+    check_file_list(&files.files)?;
+    let watch = files.watch;
+
+    // A single file is added to the destination, so nothing else there is
+    // deleted or replaced with a staged tree.
+    if files.single_file && (options.delete || options.staging) {
+        println!("the sender sends a single file, --delete and --staging are ignored");
+        options.delete = false;
+        options.staging = false;
+    }
+
+    // In staging mode everything is received into a copy of the tree that
+    // replaces the current one at the end.
+    let staging = match options.staging && !options.dry_run {
+        true => Some(staging::prepare(&root, &files.files)?),
+        false => None
+    };
+    let dest = match &staging {
+        Some(staging) => Arc::new(DestDir::open(staging)?),
+        None => Arc::new(DestDir::open(&root)?)
+    };
 
     println!("{} files to receive", files.files.len());
 
+    // The staged tree only holds the sender's files, so there is nothing to delete.
+    let deletions = match options.delete && staging.is_none() {
+        true => {
+            let deletions = mirror::plan_deletions(&root, &files.files, &options.scan)?;
+            match options.dry_run || mirror::confirm_deletions(&deletions, options.max_delete)? {
+                true => Some(deletions),
+                false => {
+                    println!("deletion cancelled");
+                    None
+                }
+            }
+        }
+        false => None
+    };
+
     enum FileStreamState {
         NotStarted,
         Working,
-        Finished
+        Finished,
+        Skipped,
+        Dir,
+        Symlink,
+        HardLink,
+        Kept
     }
 
     let mut file_streams: Vec<FileStreamState> = files.files
         .iter()
         .map(|f| {
-            match f.size {
-                0 => FileStreamState::Finished,
+            match (&f.kind, f.size) {
+                (EntryKind::Dir, _) => FileStreamState::Dir,
+                (EntryKind::HardLink(_), _) => FileStreamState::HardLink,
+                _ if is_unchanged(&dest, f, staging.is_some()) => FileStreamState::Skipped,
+                (EntryKind::Symlink(_), _) => FileStreamState::Symlink,
                 _ => FileStreamState::NotStarted
             }
         }).collect();
 
-    let mut files_to_receive = file_streams.iter()
-        .filter(|state| {
+    // Entries that would replace something already there go by the conflict
+    // policy. Directories go first, so whatever is below a kept or renamed
+    // directory follows it.
+    let mut order: Vec<usize> = (0..files.files.len()).collect();
+    order.sort_by_key(|i| {
+        let f = &files.files[*i];
+        (f.kind != EntryKind::Dir, f.partial_path.len())
+    });
+
+    let mut conflicts = vec![];
+    let mut renamed = HashMap::<Vec<String>, Vec<String>>::new();
+    let mut kept_dirs: Vec<Vec<String>> = vec![];
+    let mut renamed_dirs: Vec<(Vec<String>, Vec<String>)> = vec![];
+    for i in order {
+        let f = &mut files.files[i];
+        let state = &mut file_streams[i];
+
+        f.partial_path = move_below(&f.partial_path, &renamed_dirs);
+        if kept_dirs.iter().any(|dir| f.partial_path.starts_with(dir)) {
+            *state = FileStreamState::Kept;
+            continue;
+        }
+
+        match (&*state, &f.kind) {
+            (FileStreamState::HardLink, EntryKind::HardLink(target)) if is_linked(&dest, &f.partial_path, target) => {
+                continue;
+            }
+            (FileStreamState::NotStarted | FileStreamState::Symlink | FileStreamState::HardLink | FileStreamState::Dir, _) => { }
+            _ => {
+                continue;
+            }
+        }
+
+        let resolution = match conflicts::resolve(&dest, f, options.conflict)? {
+            Some(resolution) => resolution,
+            None => {
+                continue;
+            }
+        };
+        let path = files::list_to_path(&f.partial_path);
+        match &resolution {
+            Resolution::Overwrite => { }
+            Resolution::Keep => {
+                if f.kind == EntryKind::Dir {
+                    kept_dirs.push(f.partial_path.clone());
+                }
+                *state = FileStreamState::Kept;
+            }
+            Resolution::Rename(path) => {
+                if f.kind == EntryKind::Dir {
+                    renamed_dirs.push((f.partial_path.clone(), path.clone()));
+                }
+                renamed.insert(f.partial_path.clone(), path.clone());
+                f.partial_path = path.clone();
+            }
+        }
+        conflicts.push(Conflict {
+            path,
+            resolution
+        });
+    }
+    // Links follow their targets, and are not made into a kept directory.
+    for (f, state) in files.files.iter_mut().zip(file_streams.iter_mut()) {
+        if let EntryKind::HardLink(target) = &mut f.kind {
+            *target = match renamed.get(target) {
+                Some(path) => path.clone(),
+                None => move_below(target, &renamed_dirs)
+            };
+            if kept_dirs.iter().any(|dir| target.starts_with(dir)) {
+                *state = FileStreamState::Kept;
+            }
+        }
+    }
+
+    // A dry run stops here, with the plan printed and no data requested.
+    if options.dry_run {
+        let mut actions = vec![];
+        for (f, state) in files.files.iter().zip(file_streams.iter()) {
+            let path = files::list_to_path(&f.partial_path);
+            let exists = dest.metadata(&f.partial_path).is_ok();
+            let action = match (state, &f.kind) {
+                (FileStreamState::Dir, _) if exists => {
+                    continue;
+                }
+                (FileStreamState::Dir, _) => Action::CreateDir,
+                (FileStreamState::Skipped, _) => Action::Skip(f.size),
+                (FileStreamState::Kept, _) => Action::Keep,
+                (FileStreamState::HardLink, EntryKind::HardLink(target)) => Action::Link(files::list_to_path(target)),
+                _ if exists => Action::Overwrite(f.size),
+                _ => Action::Create(f.size)
+            };
+            actions.push((path, action));
+        }
+
+        dry_run::print_plan(&Plan {
+            actions,
+            deletions,
+            max_delete: options.max_delete
+        });
+
+        let message = DSMessage {
+            message_type: DSMessageType::DownloadFiles(vec![])
+        };
+        let json = serde_json::to_string(&message)?;
+        stream.write_all(json.as_bytes())?;
+
+        return Ok((tcp_endpoint, watch));
+    }
+
+    let writes: Vec<u32> = file_streams.iter()
+        .enumerate()
+        .filter(|(_, state)| {
             match state {
                 FileStreamState::NotStarted => true,
                 _ => false
             }
+        })
+        .map(|(i, _)| i as u32)
+        .collect();
+
+    // Empty files are written locally and have nothing to download.
+    let downloads: Vec<u32> = writes.iter()
+        .filter(|id| files.files[**id as usize].size > 0)
+        .copied()
+        .collect();
+
+    // Unchanged files are not written again, but their permissions may still differ.
+    // The mode comes last, since it may not allow setting attributes.
+    for (f, state) in files.files.iter().zip(file_streams.iter()) {
+        if let (EntryKind::File, FileStreamState::Skipped) = (&f.kind, state) {
+            apply_xattrs(&dest, f, &logger)?;
+            update_permissions(&dest, f)?;
+        }
+    }
+
+    let skipped = file_streams.iter()
+        .filter(|state| {
+            match state {
+                FileStreamState::Skipped => true,
+                _ => false
+            }
         }).count();
 
+    let mut files_to_receive = writes.len();
+
+    let partials: Vec<FilePrefix> = downloads.iter()
+        .filter_map(|id| {
+            let f = &files.files[*id as usize];
+            partial_prefix(&dest, *id, f, options.inplace)
+        })
+        .collect();
+
+    let mut modes: Vec<WriteMode> = vec![WriteMode::Create; files.files.len()];
+    if !partials.is_empty() {
+        let candidates: Vec<(u32, u64)> = partials.iter()
+            .map(|p| (p.id, p.size))
+            .collect();
+
+        let message = DSMessage {
+            message_type: DSMessageType::CheckPrefixes(partials)
+        };
+        let json = serde_json::to_string(&message)?;
+        stream.write_all(json.as_bytes())?;
+
+        let prefixes = reader.read_json()?;
+        let prefixes: MessagePrefixes = serde_json::from_value(prefixes)?;
+
+        for (id, size) in candidates {
+            if prefixes.matching.contains(&id) {
+                modes[id as usize] = WriteMode::Resume(size);
+            }
+        }
+        println!("{} partial files to resume", prefixes.matching.len());
+    }
+
+    if options.delta {
+        let mut deltas = vec![];
+        for id in downloads.iter() {
+            if let WriteMode::Resume(_) = modes[*id as usize] {
+                continue;
+            }
+
+            let f = &files.files[*id as usize];
+            let basis = match dest.open_file(&f.partial_path) {
+                Ok(basis) => basis,
+                Err(_) => {
+                    continue;
+                }
+            };
+            match basis.metadata() {
+                Ok(meta) if meta.is_file() && meta.len() >= files::DELTA_MIN_SIZE => { }
+                _ => {
+                    continue;
+                }
+            }
+
+            let signature = files::compute_signature(basis)?;
+            modes[*id as usize] = WriteMode::Delta;
+            deltas.push(FileDelta {
+                id: *id,
+                signature
+            });
+        }
+
+        if !deltas.is_empty() {
+            println!("{} files to update with delta transfer", deltas.len());
+
+            let message = DSMessage {
+                message_type: DSMessageType::Signatures(deltas)
+            };
+            let json = serde_json::to_string(&message)?;
+            stream.write_all(json.as_bytes())?;
+        }
+    }
+
+    // The whole directory skeleton is in place before any file data arrives.
+    let mut dirs = 0;
+    for (f, state) in files.files.iter().zip(file_streams.iter_mut()) {
+        if let FileStreamState::Dir = state {
+            if dest.create_dir(&f.partial_path)? {
+                dirs += 1;
+            }
+            *state = FileStreamState::Finished;
+        }
+    }
+    if dirs > 0 {
+        println!("{} directories created", dirs);
+    }
+
+    let mut symlinks = 0;
+    for (f, state) in files.files.iter().zip(file_streams.iter_mut()) {
+        if let (EntryKind::Symlink(target), FileStreamState::Symlink) = (&f.kind, &state) {
+            create_symlink(&dest, f, target)?;
+            apply_xattrs(&dest, f, &logger)?;
+            *state = FileStreamState::Finished;
+            symlinks += 1;
+        }
+    }
+    if symlinks > 0 {
+        println!("{} symbolic links created", symlinks);
+    }
+
+    let message = DSMessage {
+        message_type: DSMessageType::DownloadFiles(downloads.clone())
+    };
+    let json = serde_json::to_string(&message)?;
+    stream.write_all(json.as_bytes())?;
+
+    // Directory times are restored at the very end, since writing and
+    // deleting files inside a directory changes its modification time.
+    let mut dir_entries: Vec<FileEntry> = files.files.iter()
+        .zip(file_streams.iter())
+        .filter(|(f, state)| f.kind == EntryKind::Dir && !matches!(state, FileStreamState::Kept))
+        .map(|(f, _)| f.clone())
+        .collect();
+    dir_entries.sort_by_key(|f| std::cmp::Reverse(f.partial_path.len()));
+
+    // Hard links are made once the files they point to are complete, since
+    // a delta transfer replaces the file with a new one.
+    let hard_links: Vec<(Vec<String>, Vec<String>)> = files.files.iter()
+        .zip(file_streams.iter())
+        .filter_map(|(f, state)| {
+            match (&f.kind, state) {
+                (EntryKind::HardLink(target), FileStreamState::HardLink) => Some((f.partial_path.clone(), target.clone())),
+                _ => None
+            }
+        })
+        .collect();
+
     let (slot_send, slot_receive) = channel();
     for _ in 0..crate::PARALLEL_TRANSFERS {
         slot_send.send(())?;
@@ -128,50 +617,64 @@ pub fn receive_files(
     let pool_clone = pool.clone();
     let logger_clone = logger.clone();
 
-    let root = root.clone();
+    let dest_clone = Arc::clone(&dest);
+    // Staged files start out as links to the current ones and must not be written to.
+    let inplace = options.inplace && staging.is_none();
 
-    let tcp_endpoint = Arc::new(Mutex::new(tcp_endpoint));
-    let tcp_endpoint_clone = Arc::clone(&tcp_endpoint);
+    let (endpoint_send, endpoint_receive) = channel();
 
-    pool.execute(move || -> Result<(), GenericError> {
-        let tcp_endpoint = &mut *tcp_endpoint_clone.lock().unwrap();
+    pool.execute_forwarding(fs_send.clone(), move || -> Result<(), GenericError> {
+        let mut tcp_endpoint = tcp_endpoint;
 
         let pool = pool_clone;
         let logger = logger_clone;
+        let dest = dest_clone;
 
         let writer_pool = ThreadPool::new(crate::PARALLEL_TRANSFERS);
 
-        for (i, f) in files.files.iter().enumerate() {
-            if f.size == 0 {
-                continue;
-            }
-
-            let id = i as u32;
+        for id in writes {
+            let f = &files.files[id as usize];
 
             slot_receive.recv()?;
             fs_send.send(FileStreamMessage::Start(id))?;
 
-            let file_path = files::list_to_path(&f.partial_path);
-            let file_relative_path = file_path.to_owned();
-            let file_path = root.join(file_path);
+            let file_relative_path = files::list_to_path(&f.partial_path);
+
+            // The logger has to know about the file before its writer can report it finished.
+            if f.size == 0 {
+                logger.send(LoggerMessage::StartFile {
+                    id,
+                    name: file_relative_path.to_string_lossy().into_owned(),
+                    size: 0
+                })?;
+            }
+            let mode = modes[id as usize];
+            let offset = match mode {
+                WriteMode::Resume(offset) => offset,
+                _ => 0
+            };
             let writer = FileWriter::new(
                 id,
-                f.size,
-                &file_path,
+                f,
+                mode,
+                inplace,
+                Arc::clone(&dest),
                 fs_send.clone(),
                 writer_pool.clone())?;
             let writer = Arc::new(writer);
-            let file_size = f.size;
+            let file_size = f.size - offset;
+
+            if f.size == 0 {
+                continue;
+            }
 
             for _ in 0..2 {
                 let mut stream = tcp_endpoint.get_connection()?;
                 let logger = logger.clone();
-                let name = file_relative_path.to_str()
-                    .ok_or(new_custom_error("no file path"))?
-                    .to_owned();
+                let name = file_relative_path.to_string_lossy().into_owned();
 
                 let writer = Arc::clone(&writer);
-                pool.execute(move || -> Result<(), GenericError> {
+                pool.execute_forwarding(fs_send.clone(), move || -> Result<(), GenericError> {
                     logger.send(LoggerMessage::StartFile {
                         id: id,
                         name: name,
@@ -179,7 +682,8 @@ pub fn receive_files(
                     })?;
 
                     let download = DownloadFile {
-                        id
+                        id,
+                        offset
                     };
                     let download = serde_json::to_string(&download)?;
                     stream.write(download.as_bytes())?;
@@ -206,10 +710,11 @@ pub fn receive_files(
             }
         }
 
+        endpoint_send.send(tcp_endpoint)?;
         Ok(())
     });
 
-    loop {
+    while files_to_receive > 0 {
         let message = fs_receive.recv()?;
 
         match message {
@@ -241,14 +746,46 @@ pub fn receive_files(
                     }
                 }
             }
+            FileStreamMessage::Warning(_, message) => {
+                logger.send(LoggerMessage::Warning { message })?;
+            }
+            FileStreamMessage::Failed(err) => {
+                return Err(err);
+            }
         }
+    }
 
-        if files_to_receive == 0 {
-            break;
+    let tcp_endpoint = endpoint_receive.recv()?;
+
+    let mut links_created = 0;
+    for (path, target) in hard_links.iter() {
+        if create_hard_link(&dest, path, target)? {
+            links_created += 1;
         }
     }
+    if links_created > 0 {
+        println!("{} hard links created", links_created);
+    }
 
-    drop(tcp_endpoint);
+    println!("{} files skipped (unchanged)", skipped);
+    // Replacing what is there is what a transfer normally does, so it is
+    // only listed when another policy was asked for.
+    if options.conflict != ConflictPolicy::Overwrite {
+        conflicts::print_summary(&conflicts);
+    }
 
-    Ok(())
+    if let Some(deletions) = deletions {
+        mirror::delete(&dest, deletions)?;
+    }
+
+    for dir in dir_entries.iter() {
+        apply_xattrs(&dest, dir, &logger)?;
+        files::apply_metadata(&dest.open_dir(&dir.partial_path)?, dir)?;
+    }
+
+    if let Some(staging) = staging {
+        staging::commit(&root, &staging, options.keep_old)?;
+    }
+
+    Ok((tcp_endpoint, watch))
 }
@@ -1,11 +1,12 @@
-use std::{io::Write, net::TcpStream, path::PathBuf, sync::mpsc::{channel, Sender}};
+use std::{collections::HashMap, io::Write, net::TcpStream, path::PathBuf, sync::mpsc::{channel, Sender}};
 
+use common::FileStreamMessage;
 use errors::{new_custom_error, GenericError};
-use files::{FileReaderManager, ReaderResult};
+use files::{DirWatcher, FileEntry, FileReaderManager, ReaderResult};
 use net::{JSONReader, TcpEndpoint};
 use thread_pool::ThreadPool;
 
-use crate::{logger::LoggerMessage, messages::{DSMessage, DSMessageType, DownloadFile, MessageFiles}};
+use crate::{browse, logger::LoggerMessage, messages::{DSMessage, DSMessageType, DownloadFile, MessageFiles, MessageListing, MessagePrefixes}, options::Options, watch};
 
 fn write_bytes(stream: &mut TcpStream, buf: &[u8]) ->
     Result<(), GenericError> {
@@ -22,10 +23,42 @@ fn write_bytes(stream: &mut TcpStream, buf: &[u8]) ->
     Ok(())
 }
 
-pub fn send_files(
-    mut tcp_endpoint: impl TcpEndpoint,
+// Sends `source`, which is either a directory or a single file.
+pub fn send_files<E: TcpEndpoint>(
+    tcp_endpoint: E,
+    source: PathBuf,
+    options: Options,
+    logger: Sender<LoggerMessage>) -> Result<E, GenericError> {
+
+    let dir = files::source_root(&source)?;
+
+    // The watch starts before the scan, so nothing that changes during the
+    // first transfer is missed.
+    let watcher = match options.watch {
+        true => Some(DirWatcher::new(&dir)?),
+        false => None
+    };
+
+    let files = files::scan_source(&source, &options.scan)?;
+    let single_file = !std::fs::metadata(&source)?.is_dir();
+    let tcp_endpoint = send_file_list(tcp_endpoint, dir.clone(), files.clone(), watcher.is_some(), single_file, logger.clone())?;
+
+    match watcher {
+        Some(watcher) => watch::send_changes(tcp_endpoint, source, dir, options, files, watcher, logger),
+        None => Ok(tcp_endpoint)
+    }
+}
+
+// Serves one transfer of `files`, which are relative to `dir`. The
+// endpoint is handed back once the receiver has everything, so the
+// same connection can be used again.
+pub fn send_file_list<E: TcpEndpoint>(
+    mut tcp_endpoint: E,
     dir: PathBuf,
-    logger: Sender<LoggerMessage>) -> Result<(), GenericError> {
+    mut files: Vec<FileEntry>,
+    watch: bool,
+    single_file: bool,
+    logger: Sender<LoggerMessage>) -> Result<E, GenericError> {
 
     let mut main_stream = tcp_endpoint.wait_for_connection()?;
     let mut reader = {
@@ -33,10 +66,6 @@ pub fn send_files(
         JSONReader::new(main_stream_clone)
     };
 
-    let json = reader.read_json()?;
-    let message: DSMessage = serde_json::from_value(json)?;
-    let files = files::get_files_in_dir(&dir)?;
-
     #[derive(Debug)]
     enum FileStreamState {
         NotStarted,
@@ -45,40 +74,93 @@ pub fn send_files(
     }
 
     let mut file_streams: Vec<FileStreamState> = files.iter()
-        .map(|f| {
-            match f.size {
-                0 => FileStreamState::Finished,
-                _ => FileStreamState::NotStarted
-            }
-        }).collect();
+        .map(|_| FileStreamState::Finished)
+        .collect();
 
-    let mut files_to_send = file_streams.iter()
-        .filter(|f| {
-            match f {
-                FileStreamState::NotStarted => true,
-                _ => false
-            }
-        }).count();
+    let mut signatures = HashMap::new();
 
-    let manager = FileReaderManager::new(dir, &files, crate::PARALLEL_TRANSFERS);
+    let (mut files_to_send, connections) = loop {
+        let json = reader.read_json()?;
+        let message: DSMessage = serde_json::from_value(json)?;
 
-    match message.message_type {
-        DSMessageType::GetFileList => {
-            let files = MessageFiles {
-                files
-            };
+        match message.message_type {
+            DSMessageType::GetFileList => {
+                let files = MessageFiles {
+                    files: files.clone(),
+                    watch,
+                    single_file
+                };
+
+                let json = serde_json::to_string(&files)?;
+                main_stream.write_all(json.as_bytes())?;
+            }
+            DSMessageType::CheckPrefixes(prefixes) => {
+                let mut matching = vec![];
+                for prefix in prefixes {
+                    let f = files.get(prefix.id as usize)
+                        .ok_or(new_custom_error("unknown file id"))?;
+                    if prefix.size >= f.size {
+                        continue;
+                    }
 
-            let json = serde_json::to_string(&files)?;
-            main_stream.write(json.as_bytes())?;
+                    let path = dir.join(files::list_to_path(&f.partial_path));
+                    let hash = files::hash_file_prefix(std::fs::File::open(&path)?, prefix.size)?;
+                    if hash == prefix.hash {
+                        matching.push(prefix.id);
+                    }
+                }
+
+                let prefixes = MessagePrefixes {
+                    matching
+                };
+                let json = serde_json::to_string(&prefixes)?;
+                main_stream.write_all(json.as_bytes())?;
+            }
+            DSMessageType::Signatures(deltas) => {
+                for delta in deltas {
+                    delta.signature.check()?;
+                    signatures.insert(delta.id, delta.signature);
+                }
+            }
+            DSMessageType::DownloadFiles(ids) => {
+                // The receiver opens two connections for every file it downloads.
+                let connections = 2 * ids.len();
+                for id in ids {
+                    let stream_state = file_streams.get_mut(id as usize)
+                        .ok_or(new_custom_error("unknown file id"))?;
+                    *stream_state = FileStreamState::NotStarted;
+                }
+                let count = file_streams.iter()
+                    .filter(|f| {
+                        match f {
+                            FileStreamState::NotStarted => true,
+                            _ => false
+                        }
+                    }).count();
+                break (count, connections);
+            }
+            DSMessageType::ListDir(path) => {
+                let listing = MessageListing {
+                    entries: browse::list_dir(&files, &path)
+                };
+                let json = serde_json::to_string(&listing)?;
+                main_stream.write_all(json.as_bytes())?;
+            }
+            // Only the files the receiver picked are listed and sent from here on.
+            DSMessageType::SelectFiles(patterns) => {
+                files = files::select_files(&dir, &files, &patterns)?;
+                file_streams = files.iter()
+                    .map(|_| FileStreamState::Finished)
+                    .collect();
+            }
+            DSMessageType::SyncState | DSMessageType::SyncPlan(_) | DSMessageType::WatchChanges => {
+                return Err(new_custom_error("protocol error: unexpected message during a transfer"));
+            }
         }
-    }
+    };
 
+    let manager = FileReaderManager::new(dir, &files, signatures, crate::PARALLEL_TRANSFERS);
 
-    #[derive(Debug)]
-    enum FileStreamMessage {
-        Start(u32),
-        Finish(u32)
-    }
     let (fs_sender, fs_receiver) = channel();
     let fs_sender_clone = fs_sender.clone();
 
@@ -92,12 +174,14 @@ pub fn send_files(
         slot_sender.send(())?;
     }
 
-    pool.execute(move || -> Result<(), GenericError> {
+    let (endpoint_sender, endpoint_receiver) = channel();
+
+    pool.execute_forwarding(fs_sender.clone(), move || -> Result<(), GenericError> {
         let fs_sender = fs_sender_clone;
         let pool = pool_clone;
         let logger = logger_clone;
 
-        loop {
+        for _ in 0..connections {
             let mut stream = tcp_endpoint.wait_for_connection()?;
 
             let mut reader = { 
@@ -109,7 +193,7 @@ pub fn send_files(
             let download: DownloadFile = serde_json::from_value(message)?;
             let id = download.id;
             let reader = { 
-                let reader_result = manager.get_reader(id);
+                let reader_result = manager.get_reader(id, download.offset);
                 match reader_result {
                     ReaderResult::NoReader => None,
                     ReaderResult::FirstInstance(reader) => {
@@ -125,15 +209,15 @@ pub fn send_files(
             let sender = fs_sender.clone();
             let logger = logger.clone();
             if let Some(reader) = reader {
-                pool.execute(move || -> Result<(), GenericError> {
+                pool.execute_forwarding(sender.clone(), move || -> Result<(), GenericError> {
                     sender.send(FileStreamMessage::Start(id))?;
                     logger.send(LoggerMessage::StartFile {
                         id: id,
                         name: reader.name.to_owned(), 
-                        size: reader.size
+                        size: reader.size - reader.offset
                     })?;
                     loop {
-                        let chunk = reader.get_chunk();
+                        let chunk = reader.get_chunk()?;
 
                         match chunk {
                             Some(chunk) => {
@@ -157,9 +241,12 @@ pub fn send_files(
                 });
             }
         }
+
+        endpoint_sender.send(tcp_endpoint)?;
+        Ok(())
     });
 
-    loop {
+    while files_to_send > 0 {
         let mess = fs_receiver.recv()?;
 
         match mess {
@@ -189,7 +276,8 @@ pub fn send_files(
                             id
                         })?;
 
-                        slot_sender.send(())?;
+                        // The acceptor is gone once every connection is in.
+                        let _ = slot_sender.send(());
                     }
                     FileStreamState::Working(x) if *x > 1 => {
                         *x -= 1;
@@ -197,15 +285,15 @@ pub fn send_files(
                     _ => { }
                 }
             }
-        }
-
-        if files_to_send == 0 {
-            break;
+            FileStreamMessage::Warning(_, _) => { }
+            FileStreamMessage::Failed(err) => {
+                return Err(err);
+            }
         }
     }
 
-
-    Ok(())
+    let tcp_endpoint = endpoint_receiver.recv()?;
+    Ok(tcp_endpoint)
 }
 
 
@@ -1,7 +1,8 @@
-use std::{collections::{HashMap, VecDeque}, io::stdout, sync::mpsc::Receiver, time::SystemTime};
+use std::{collections::{HashMap, VecDeque}, io::stdout, sync::{atomic::{AtomicU32, Ordering}, mpsc::{channel, Receiver, Sender}}, time::SystemTime};
 
 use crossterm::{cursor, execute, terminal};
 use errors::{new_custom_error, GenericError};
+use thread_pool::ThreadPool;
 
 pub enum LoggerMessage {
     StartFile {
@@ -16,6 +17,9 @@ pub enum LoggerMessage {
     FinishFile {
         id: u32
     },
+    Warning {
+        message: String
+    },
 }
 
 #[derive(Debug)]
@@ -32,7 +36,46 @@ enum FileState {
     ReportedFile
 }
 
-fn format_bytes(bytes: u64) -> String {
+static NEXT_SESSION_ID: AtomicU32 = AtomicU32::new(0);
+
+// Sessions number their files from zero, so the files of each session are
+// given ids of their own before they reach the shared progress display.
+pub fn session_logger(logger: Sender<LoggerMessage>, pool: &ThreadPool) -> Sender<LoggerMessage> {
+    let (sender, receiver) = channel();
+
+    pool.execute(move || -> Result<(), GenericError> {
+        let mut ids = HashMap::<u32, u32>::new();
+        let mut map_id = |id: u32| -> u32 {
+            *ids.entry(id).or_insert_with(|| NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed))
+        };
+
+        // Ends once the session is over and has dropped its senders.
+        while let Ok(message) = receiver.recv() {
+            let message = match message {
+                LoggerMessage::StartFile { id, name, size } => LoggerMessage::StartFile {
+                    id: map_id(id),
+                    name,
+                    size
+                },
+                LoggerMessage::AddData { id, data } => LoggerMessage::AddData {
+                    id: map_id(id),
+                    data
+                },
+                LoggerMessage::FinishFile { id } => LoggerMessage::FinishFile {
+                    id: map_id(id)
+                },
+                message => message
+            };
+            logger.send(message)?;
+        }
+
+        Ok(())
+    });
+
+    sender
+}
+
+pub fn format_bytes(bytes: u64) -> String {
     let kb = 1024;
     let mb = kb * 1024;
     let gb = mb * 1024;
@@ -55,7 +98,10 @@ fn format_bytes(bytes: u64) -> String {
 }
 
 pub fn progress_string(progress: (u64, u64), last_update: &(SystemTime, u64), name: &str) -> String {
-    let progress_float = progress.0 as f32 / progress.1 as f32;
+    let progress_float = match progress.1 {
+        0 => 1.0,
+        size => progress.0 as f32 / size as f32
+    };
     let mut bar: String = "".into();
     let len = 15;
     for i in 0..len {
@@ -105,15 +151,20 @@ pub fn log_progress(receiver: Receiver<LoggerMessage>)
         record
     }
 
+    let mut warnings = Vec::<String>::new();
+
     let mut first_run = true;
+    // Several sessions can have more files in progress than a single transfer.
+    let mut progress_lines = crate::PARALLEL_TRANSFERS as usize;
     let mut stdout = stdout();
     execute!(stdout, cursor::Hide)?;
     loop {
         let message = receiver.recv()?;
         match message {
             LoggerMessage::StartFile { id, name, size } => {
+                // A sync runs several transfers, which count their ids from zero again.
                 let file = find_file(id, &mut files);
-                if let None = file {
+                if let None | Some(FileState::ReportedFile) = file {
                     let time_stamp = std::time::SystemTime::now();
                     let mut stamps = VecDeque::new();
                     stamps.push_back((time_stamp, 0));
@@ -164,12 +215,15 @@ pub fn log_progress(receiver: Receiver<LoggerMessage>)
                     _ => { }
                 }
             }
+            LoggerMessage::Warning { message } => {
+                warnings.push(message);
+            }
         }
 
         execute!(stdout, terminal::BeginSynchronizedUpdate)?;
 
         if !first_run {
-            execute!(stdout, cursor::MoveUp(crate::PARALLEL_TRANSFERS as u16))?;
+            execute!(stdout, cursor::MoveUp(progress_lines as u16))?;
             execute!(stdout, terminal::Clear(terminal::ClearType::FromCursorDown))?;
         }
         first_run = false;
@@ -182,6 +236,10 @@ pub fn log_progress(receiver: Receiver<LoggerMessage>)
                 } 
             });
 
+        for warning in warnings.drain(..) {
+            println!("warning: {}", warning);
+        }
+
         for f in closed {
             match f {
                 FileState::ClosedFile { name } => {
@@ -202,9 +260,10 @@ pub fn log_progress(receiver: Receiver<LoggerMessage>)
                 }
             })
             .count();
-        for _ in 0..(crate::PARALLEL_TRANSFERS as usize - in_progress) {
+        for _ in in_progress..(crate::PARALLEL_TRANSFERS as usize) {
             println!();
         }
+        progress_lines = in_progress.max(crate::PARALLEL_TRANSFERS as usize);
 
         for f in files.values() {
             if let FileState::FileProgress { last_update, name, size, data } = f {
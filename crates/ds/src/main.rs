@@ -1,8 +1,10 @@
-use std::{io::{stdin, stdout, Write}, net::SocketAddr, path::PathBuf, str::FromStr, sync::mpsc::channel};
+use std::{io::{stdin, stdout, Write}, net::SocketAddr, path::PathBuf, str::FromStr, sync::{mpsc::{channel, Sender}, Mutex}};
 
 use crossterm::{cursor, execute};
 use errors::{new_custom_error, GenericError};
-use net::{new_client_endpoint, new_server_endpoint};
+use logger::LoggerMessage;
+use net::{new_client_endpoint, new_server, TcpServerSession};
+use options::Options;
 use network_interface::NetworkInterfaceConfig;
 use thread_pool::ThreadPool;
 
@@ -10,22 +12,49 @@ mod file_sender;
 mod file_receiver;
 mod messages;
 mod logger;
+mod browse;
+mod conflicts;
+mod dry_run;
+mod mirror;
+mod options;
+mod staging;
+mod sync;
+mod watch;
 
 static PARALLEL_TRANSFERS: u8 = 4;
 
-fn get_local_params() -> Result<(String, String), GenericError> {
-    let cur_dir = std::env::current_dir()?;
-    let cur_dir = cur_dir.to_str()
-        .ok_or(new_custom_error("cur dir error"))?;
-    Ok((cur_dir.into(), cur_dir.into()))
+// The sessions of a server run at the same time but share one terminal.
+// Whatever asks the user something holds this, so the questions of two
+// sessions and their answers never get mixed up.
+static PROMPT: Mutex<()> = Mutex::new(());
+
+// The path given on the command line, or the current directory. Files are
+// sent from it, which may also be a single file, or received into it, in
+// which case it is created if it is not there yet.
+fn get_local_path(path: Option<&String>, transfer: &Transfer) -> Result<PathBuf, GenericError> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => std::env::current_dir()?
+    };
+
+    match transfer {
+        Transfer::SendFiles => { }
+        _ => {
+            std::fs::create_dir_all(&path)?;
+        }
+    }
+
+    Ok(std::fs::canonicalize(&path)?)
 }
 
+#[derive(Clone, Copy)]
 enum Transfer {
     SendFiles,
     ReceiveFiles,
+    TwoWaySync,
 }
 fn ask_for_transfer_type() -> Result<Transfer, GenericError> {
-    print!("(S)end or (R)eceive files? ");
+    print!("(S)end, (R)eceive or (T)wo-way sync? ");
     stdout().flush().unwrap();
     let stdin = stdin();
     let mut buf = String::new();
@@ -37,10 +66,68 @@ fn ask_for_transfer_type() -> Result<Transfer, GenericError> {
     if buf.eq_ignore_ascii_case("R") {
         return Ok(Transfer::ReceiveFiles);
     }
+    if buf.eq_ignore_ascii_case("T") {
+        return Ok(Transfer::TwoWaySync);
+    }
 
     Err(new_custom_error("transfer type not parsed"))
 }
 
+// Runs the transfer of one client on a pool of its own, so an error ends
+// that session only and the server goes on serving the others.
+fn serve_session(
+    session: TcpServerSession,
+    peer: SocketAddr,
+    transfer: Transfer,
+    dir: PathBuf,
+    options: Options,
+    logger: Sender<LoggerMessage>) {
+
+    let closer = match session.closer() {
+        Ok(closer) => closer,
+        Err(err) => {
+            println!("{} failed: {}", peer, err);
+            return;
+        }
+    };
+
+    let (report_send, report_receive) = channel();
+    let pool = ThreadPool::with_report_channel(2, report_send.clone());
+    let logger = logger::session_logger(logger, &pool);
+
+    pool.execute(move || -> Result<(), GenericError> {
+        match transfer {
+            Transfer::SendFiles => {
+                file_sender::send_files(session, dir, options, logger)?;
+            }
+            Transfer::ReceiveFiles => {
+                file_receiver::receive_files(session, dir, options, logger)?;
+            }
+            Transfer::TwoWaySync => {
+                sync::respond(session, dir, options, logger)?;
+            }
+        }
+        report_send.send(None)?;
+
+        Ok(())
+    });
+
+    let monitor = ThreadPool::new(1);
+    monitor.execute(move || -> Result<(), GenericError> {
+        let report = report_receive.recv()?;
+        // Wakes up whatever is still waiting on the client, so the tasks
+        // of a failed session end and its pools go away.
+        closer.close();
+
+        match report {
+            Some(err) => println!("{} failed: {}", peer, err),
+            None => println!("{} done", peer)
+        }
+
+        Ok(())
+    });
+}
+
 fn main() -> Result<(), GenericError> {
     ctrlc::set_handler(|| {
         let report_channel = thread_pool::get_report_channel();
@@ -50,7 +137,7 @@ fn main() -> Result<(), GenericError> {
         new_custom_error(&err)
     })?;
 
-    let args: Vec<String> = std::env::args().collect();
+    let (args, options) = options::parse_args(std::env::args().collect())?;
     if args.len() < 2 {
         return Err(new_custom_error("server or client"));
     }
@@ -73,7 +160,7 @@ fn main() -> Result<(), GenericError> {
         let run: &str = &args[1];
         match run {
             "server" => {
-                let (server_end, addr) = new_server_endpoint(None)?;
+                let (server, addr) = new_server(None)?;
                 let port = addr.port();
                 println!("{:?}", addr);
 
@@ -103,15 +190,14 @@ fn main() -> Result<(), GenericError> {
                     }
                 }
 
-                let path = get_local_params()?.0;
-                let dir = PathBuf::from_str(&path)?;
-                match transfer {
-                    Transfer::SendFiles => {
-                        file_sender::send_files(server_end, dir, logger_send)?;
-                    }
-                    Transfer::ReceiveFiles => {
-                        file_receiver::receive_files(server_end, dir, logger_send)?;
-                    }
+                let dir = get_local_path(args.get(2), &transfer)?;
+                // The server keeps running and serves every client that
+                // connects, each one in a session of its own.
+                loop {
+                    let session = server.accept_session()?;
+                    let peer = session.peer_addr()?;
+                    println!("{} connected", peer);
+                    serve_session(session, peer, transfer, dir.clone(), options.clone(), logger_send.clone());
                 }
             }
             "client" => {
@@ -120,14 +206,17 @@ fn main() -> Result<(), GenericError> {
                 }
                 let addr = SocketAddr::from_str(&args[2])?;
                 let client_end = new_client_endpoint(addr)?;
-                let path = get_local_params()?.1;
-                let dir = PathBuf::from_str(&path)?;
+                let dir = get_local_path(args.get(3), &transfer)?;
                 match transfer {
                     Transfer::SendFiles => {
-                        file_sender::send_files(client_end, dir, logger_send)?;
+                        file_sender::send_files(client_end, dir, options, logger_send)?;
                     }
                     Transfer::ReceiveFiles => {
-                        file_receiver::receive_files(client_end, dir, logger_send)?;
+                        file_receiver::receive_files(client_end, dir, options, logger_send)?;
+                    }
+                    // The client runs the sync and the server follows it.
+                    Transfer::TwoWaySync => {
+                        sync::drive(client_end, dir, options, logger_send)?;
                     }
                 }
             }
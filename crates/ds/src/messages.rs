@@ -1,8 +1,16 @@
-use files::FileEntry;
+use files::{EntryKind, FileEntry, FileSignature};
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum DSMessageType {
-    GetFileList
+    GetFileList,
+    CheckPrefixes(Vec<FilePrefix>),
+    Signatures(Vec<FileDelta>),
+    DownloadFiles(Vec<u32>),
+    SyncState,
+    SyncPlan(SyncPlan),
+    WatchChanges,
+    ListDir(Vec<String>),
+    SelectFiles(Vec<String>)
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -12,11 +20,86 @@ pub struct DSMessage {
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct MessageFiles {
-    pub files: Vec<FileEntry>
+    pub files: Vec<FileEntry>,
+    // The sender keeps the session open and sends changes as they happen.
+    #[serde(default)]
+    pub watch: bool,
+    // The source is a file rather than a directory, so the list is not
+    // the whole tree of the sender.
+    #[serde(default)]
+    pub single_file: bool
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct FilePrefix {
+    pub id: u32,
+    pub size: u64,
+    pub hash: String
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct MessagePrefixes {
+    pub matching: Vec<u32>
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct FileDelta {
+    pub id: u32,
+    pub signature: FileSignature
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct DownloadFile {
-    pub id: u32
+    pub id: u32,
+    pub offset: u64
+}
+
+
+// One entry of a directory of the sender's tree. Directories carry the
+// total size and number of the files below them.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListingEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub files: u64
 }
 
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct MessageListing {
+    pub entries: Vec<ListingEntry>
+}
+
+// What was deleted on the sending side since the last round of a watch.
+// The new and changed files follow as a regular transfer.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct MessageChanges {
+    pub deleted: Vec<FileEntry>
+}
+
+// One entry of a synced tree, as it is kept in the state database.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SyncRecord {
+    pub partial_path: Vec<String>,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mtime: u64,
+    pub hash: String
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct MessageSyncState {
+    pub records: Vec<SyncRecord>,
+    // New or different since the last sync.
+    pub changed: Vec<Vec<String>>,
+    // Synced last time, but gone now.
+    pub deleted: Vec<Vec<String>>
+}
+
+// What the side that runs the sync asks of the other one.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SyncPlan {
+    pub send: Vec<Vec<String>>,
+    pub delete: Vec<Vec<String>>,
+    pub conflicts: Vec<Vec<String>>
+}
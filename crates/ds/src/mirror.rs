@@ -0,0 +1,121 @@
+use std::{collections::HashSet, io::{stdin, stdout, Write}, path::{Path, PathBuf}};
+
+use errors::{new_custom_error, GenericError};
+use files::{DestDir, EntryKind, FileEntry, ScanOptions};
+
+pub struct Deletions {
+    pub files: Vec<PathBuf>,
+    pub dirs: Vec<PathBuf>
+}
+
+// Finds everything under `root` that is not part of the sender's tree.
+// Paths excluded by the local scan options are left alone.
+pub fn plan_deletions(
+    root: &Path,
+    files: &[FileEntry],
+    options: &ScanOptions) -> Result<Deletions, GenericError> {
+    let mut sender_files = HashSet::<PathBuf>::new();
+    let mut sender_dirs = HashSet::<PathBuf>::new();
+    for f in files.iter() {
+        let path = files::list_to_path(&f.partial_path);
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            sender_dirs.insert(dir.to_path_buf());
+            parent = dir.parent();
+        }
+        match f.kind {
+            EntryKind::Dir => {
+                sender_dirs.insert(path);
+            }
+            _ => {
+                sender_files.insert(path);
+                // A leftover temporary file is resumed or replaced by the transfer.
+                sender_files.insert(files::list_to_path(&files::temp_path(&f.partial_path)?));
+            }
+        };
+    }
+
+    let mut files = vec![];
+    let mut dirs = vec![];
+    for f in files::get_files_in_dir(root, options)? {
+        let path = files::list_to_path(&f.partial_path);
+        match f.kind {
+            EntryKind::Dir if !sender_dirs.contains(&path) => dirs.push(path),
+            EntryKind::Dir => { }
+            _ if !sender_files.contains(&path) => files.push(path),
+            _ => { }
+        }
+    }
+
+    // Deepest directories first, so parents are empty by the time they are removed.
+    dirs.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+
+    Ok(Deletions {
+        files,
+        dirs
+    })
+}
+
+// Every way of deleting files on the receiver stops before it starts if
+// it would delete more than the limit.
+pub fn check_max_delete(total: usize, max_delete: usize) -> Result<(), GenericError> {
+    if total > max_delete {
+        return Err(new_custom_error(&format!(
+            "{} deletions exceed the limit of {} (see --max-delete)",
+            total,
+            max_delete)));
+    }
+
+    Ok(())
+}
+
+// Prints what is going to be deleted and asks the user to go ahead.
+pub fn confirm_deletions(deletions: &Deletions, max_delete: usize) -> Result<bool, GenericError> {
+    let total = deletions.files.len() + deletions.dirs.len();
+    if total == 0 {
+        return Ok(true);
+    }
+
+    check_max_delete(total, max_delete)?;
+
+    let _prompt = crate::PROMPT.lock().unwrap();
+
+    for path in deletions.files.iter() {
+        println!("delete {}", path.display());
+    }
+    for path in deletions.dirs.iter() {
+        println!("delete {}/", path.display());
+    }
+
+    print!(
+        "Delete {} files and {} directories not present on the sender? (y/N) ",
+        deletions.files.len(),
+        deletions.dirs.len());
+    stdout().flush()?;
+
+    let mut buf = String::new();
+    stdin().read_line(&mut buf)?;
+    Ok(buf.trim().eq_ignore_ascii_case("y"))
+}
+
+pub fn delete(dest: &DestDir, deletions: Deletions) -> Result<(), GenericError> {
+    for path in deletions.files.iter() {
+        dest.remove_file(&files::path_to_list(path))?;
+    }
+
+    let mut removed_dirs = 0;
+    for path in deletions.dirs.iter() {
+        match dest.remove_dir(&files::path_to_list(path)) {
+            Ok(_) => {
+                removed_dirs += 1;
+            }
+            Err(e) => {
+                println!("could not remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    println!("{} files and {} directories deleted", deletions.files.len(), removed_dirs);
+
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use errors::{new_custom_error, GenericError};
+use files::{LinkMode, ScanOptions};
+
+use crate::conflicts::ConflictPolicy;
+
+static DEFAULT_MAX_DELETE: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub delta: bool,
+    pub delete: bool,
+    pub inplace: bool,
+    pub dry_run: bool,
+    pub staging: bool,
+    pub keep_old: bool,
+    pub watch: bool,
+    pub browse: bool,
+    pub pick: Vec<String>,
+    pub max_delete: usize,
+    pub conflict: ConflictPolicy,
+    pub scan: ScanOptions,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            delta: false,
+            delete: false,
+            inplace: false,
+            dry_run: false,
+            staging: false,
+            keep_old: false,
+            watch: false,
+            browse: false,
+            pick: vec![],
+            max_delete: DEFAULT_MAX_DELETE,
+            conflict: ConflictPolicy::Overwrite,
+            scan: ScanOptions {
+                exclude: crate::sync::state_file_excludes(),
+                ..ScanOptions::default()
+            }
+        }
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<&str>) -> Result<T, GenericError> {
+    let value = value.ok_or(new_custom_error(&format!("{} needs a value", name)))?;
+    value.parse()
+        .map_err(|_| new_custom_error(&format!("invalid value for {}: {}", name, value)))
+}
+
+// Splits the command line into positional arguments and `--` options.
+pub fn parse_args(args: Vec<String>) -> Result<(Vec<String>, Options), GenericError> {
+    let mut positional = vec![];
+    let mut options = Options::default();
+
+    for arg in args {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None)
+        };
+
+        match name {
+            "--delta" => {
+                options.delta = true;
+            }
+            "--delete" => {
+                options.delete = true;
+            }
+            "--dry-run" => {
+                options.dry_run = true;
+            }
+            "--inplace" => {
+                options.inplace = true;
+            }
+            "--staging" => {
+                options.staging = true;
+            }
+            "--keep-old" => {
+                options.keep_old = true;
+            }
+            "--watch" => {
+                options.watch = true;
+            }
+            "--browse" => {
+                options.browse = true;
+            }
+            "--pick" => {
+                options.pick.push(parse_value(name, value)?);
+            }
+            "--max-delete" => {
+                options.max_delete = parse_value(name, value)?;
+            }
+            "--conflict" => {
+                options.conflict = parse_value(name, value)?;
+            }
+            "--exclude" => {
+                options.scan.exclude.push(parse_value(name, value)?);
+            }
+            "--include" => {
+                options.scan.include.push(parse_value(name, value)?);
+            }
+            "--gitignore" => {
+                options.scan.gitignore = true;
+            }
+            "--xattrs" => {
+                options.scan.xattrs = true;
+            }
+            "--links" => {
+                options.scan.links = match value {
+                    Some("preserve") => LinkMode::Preserve,
+                    Some("follow") => LinkMode::Follow,
+                    Some("skip") => LinkMode::Skip,
+                    _ => {
+                        return Err(new_custom_error("--links must be preserve, follow or skip"));
+                    }
+                };
+            }
+            _ => {
+                return Err(new_custom_error(&format!("unknown option {}", arg)));
+            }
+        }
+    }
+
+    // Both would take the files that were not picked as gone from the sender.
+    if (options.browse || !options.pick.is_empty()) && (options.delete || options.staging) {
+        return Err(new_custom_error("--browse and --pick can't be combined with --delete or --staging"));
+    }
+
+    Ok((positional, options))
+}
@@ -0,0 +1,67 @@
+use std::path::{Path, PathBuf};
+
+use errors::{new_custom_error, GenericError};
+use files::{DestDir, EntryKind, FileEntry};
+
+// A hidden sibling of `root` with the given suffix, e.g. `.name.ds-staging`.
+fn sibling(root: &Path, suffix: &str) -> Result<PathBuf, GenericError> {
+    let name = root.file_name()
+        .ok_or(new_custom_error("the destination has no parent directory"))?;
+    let mut sibling = std::ffi::OsString::from(".");
+    sibling.push(name);
+    sibling.push(suffix);
+    Ok(root.with_file_name(sibling))
+}
+
+// Creates an empty staging directory next to `root` and links the files
+// that are already there into it, so unchanged files are not sent again
+// and changed ones can still be used for resume and delta transfers.
+// Files are always replaced in the staging tree, never written through
+// these links, so the current tree stays as it is until the swap.
+pub fn prepare(root: &Path, files: &[FileEntry]) -> Result<PathBuf, GenericError> {
+    let staging = sibling(root, ".ds-staging")?;
+    if std::fs::symlink_metadata(&staging).is_ok() {
+        println!("removing the staging directory of an earlier transfer");
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir(&staging)?;
+    std::fs::set_permissions(&staging, std::fs::metadata(root)?.permissions())?;
+
+    let current = DestDir::open(root)?;
+    let dest = DestDir::open(&staging)?;
+    for f in files.iter() {
+        if f.kind != EntryKind::File {
+            continue;
+        }
+        match current.metadata(&f.partial_path) {
+            Ok(meta) if meta.is_file() => {
+                dest.hard_link_from(&current, &f.partial_path)?;
+            }
+            _ => { }
+        }
+    }
+
+    Ok(staging)
+}
+
+// Swaps the staged tree with the current one in a single rename.
+pub fn commit(root: &Path, staging: &Path, keep_old: bool) -> Result<(), GenericError> {
+    files::exchange_dirs(staging, root)?;
+
+    // The old tree is now where the staging directory was.
+    match keep_old {
+        true => {
+            let old = sibling(root, ".ds-old")?;
+            if std::fs::symlink_metadata(&old).is_ok() {
+                std::fs::remove_dir_all(&old)?;
+            }
+            std::fs::rename(staging, &old)?;
+            println!("previous tree kept in {}", old.display());
+        }
+        false => {
+            std::fs::remove_dir_all(staging)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,586 @@
+use std::{collections::{HashMap, HashSet}, fs::File, io::{ErrorKind, Write}, path::{Path, PathBuf}, sync::mpsc::Sender};
+
+use errors::{new_custom_error, GenericError};
+use files::{DestDir, EntryKind, FileEntry, ScanOptions};
+use net::{JSONReader, TcpEndpoint};
+
+use crate::{conflicts::ConflictPolicy, file_receiver, file_sender, logger::LoggerMessage, messages::{DSMessage, DSMessageType, MessageSyncState, SyncPlan, SyncRecord}, mirror::{self, Deletions}, options::Options};
+
+// Each side keeps the tree as it was after the last sync in the root of
+// the synced directory.
+static STATE_FILE: &str = ".ds-sync-state";
+static STATE_FILE_TMP: &str = ".ds-sync-state.tmp";
+
+// The state belongs to the directory it is in, so it is never sent, and
+// a peer can't plant a file or a link under its name.
+pub fn state_file_excludes() -> Vec<String> {
+    vec![format!("/{}", STATE_FILE), format!("/{}", STATE_FILE_TMP)]
+}
+
+pub fn is_state_file(partial_path: &[String]) -> bool {
+    match partial_path {
+        [name] => name == STATE_FILE || name == STATE_FILE_TMP,
+        _ => false
+    }
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct SyncDatabase {
+    records: Vec<SyncRecord>
+}
+
+fn load_database(root: &Path) -> Result<HashMap<Vec<String>, SyncRecord>, GenericError> {
+    let data = match std::fs::read(root.join(STATE_FILE)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Ok(HashMap::new());
+        }
+        Err(e) => {
+            return Err(e.into());
+        }
+    };
+
+    let database: SyncDatabase = serde_json::from_slice(&data)?;
+    Ok(database.records.into_iter()
+        .map(|r| (r.partial_path.clone(), r))
+        .collect())
+}
+
+// Written to a temporary file first, so an interrupted save keeps the old state.
+fn save_database(root: &Path, records: Vec<SyncRecord>) -> Result<(), GenericError> {
+    let tmp = vec![STATE_FILE_TMP.to_string()];
+    let json = serde_json::to_vec(&SyncDatabase {
+        records
+    })?;
+
+    let dest = DestDir::open(root)?;
+    let mut file = dest.create_file(&tmp)?;
+    file.write_all(&json)?;
+    file.sync_all()?;
+    dest.rename(&tmp, &[STATE_FILE.to_string()])?;
+
+    Ok(())
+}
+
+// The tree as the sync sees it. Other names of a hard linked file are
+// synced as files of their own.
+fn scan(root: &Path, options: &ScanOptions) -> Result<Vec<FileEntry>, GenericError> {
+    let mut options = options.clone();
+    options.exclude.extend(state_file_excludes());
+    options.exclude.push("*.ds-part".into());
+
+    let mut files = files::get_files_in_dir(root, &options)?;
+    let sizes: HashMap<Vec<String>, u64> = files.iter()
+        .filter(|f| f.kind == EntryKind::File)
+        .map(|f| (f.partial_path.clone(), f.size))
+        .collect();
+    for f in files.iter_mut() {
+        if let EntryKind::HardLink(target) = &f.kind {
+            f.size = sizes.get(target).copied().unwrap_or(0);
+            f.kind = EntryKind::File;
+        }
+    }
+
+    Ok(files)
+}
+
+// Files are only hashed again if their size or modification time moved.
+fn make_record(root: &Path, file: &FileEntry, previous: Option<&SyncRecord>) -> Result<SyncRecord, GenericError> {
+    let hash = match &file.kind {
+        EntryKind::File => match previous {
+            Some(r) if r.kind == file.kind && r.size == file.size && r.mtime == file.mtime => r.hash.clone(),
+            _ => {
+                let f = File::open(root.join(files::list_to_path(&file.partial_path)))?;
+                files::hash_file_prefix(f, file.size)?
+            }
+        },
+        EntryKind::Symlink(target) => target.clone(),
+        _ => String::new()
+    };
+
+    Ok(SyncRecord {
+        partial_path: file.partial_path.clone(),
+        kind: file.kind.clone(),
+        size: file.size,
+        mtime: file.mtime,
+        hash
+    })
+}
+
+// Compares the tree with the state database. A file that was only touched
+// keeps its contents, so it does not count as changed.
+fn local_state(root: &Path, options: &Options) -> Result<(Vec<FileEntry>, MessageSyncState), GenericError> {
+    let database = load_database(root)?;
+    let files = scan(root, &options.scan)?;
+
+    let mut records = vec![];
+    let mut changed = vec![];
+    let mut present = HashSet::new();
+    for f in files.iter() {
+        let previous = database.get(&f.partial_path);
+        let record = make_record(root, f, previous)?;
+        match previous {
+            Some(p) if p.kind == record.kind && p.hash == record.hash => { }
+            _ => {
+                changed.push(f.partial_path.clone());
+            }
+        }
+        present.insert(f.partial_path.clone());
+        records.push(record);
+    }
+
+    let deleted = database.into_keys()
+        .filter(|path| !present.contains(path))
+        .collect();
+
+    Ok((files, MessageSyncState {
+        records,
+        changed,
+        deleted
+    }))
+}
+
+struct Side {
+    records: HashMap<Vec<String>, SyncRecord>,
+    changed: HashSet<Vec<String>>,
+    deleted: HashSet<Vec<String>>
+}
+
+enum Change<'a> {
+    Unchanged(&'a SyncRecord),
+    Changed(&'a SyncRecord),
+    Deleted,
+    // Neither there now nor at the last sync.
+    Absent
+}
+
+impl Side {
+    fn new(state: MessageSyncState) -> Self {
+        Side {
+            records: state.records.into_iter()
+                .map(|r| (r.partial_path.clone(), r))
+                .collect(),
+            changed: state.changed.into_iter().collect(),
+            deleted: state.deleted.into_iter().collect()
+        }
+    }
+
+    fn change(&self, path: &Vec<String>) -> Change<'_> {
+        match self.records.get(path) {
+            Some(r) if self.changed.contains(path) => Change::Changed(r),
+            Some(r) => Change::Unchanged(r),
+            None if self.deleted.contains(path) => Change::Deleted,
+            None => Change::Absent
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Nothing,
+    Send,
+    Receive,
+    DeleteHere,
+    DeleteThere,
+    Conflict
+}
+
+// A directory that is still there is not replaced by a file.
+fn replace(incoming: &SyncRecord, existing: &SyncRecord, decision: Decision) -> Decision {
+    match (&incoming.kind, &existing.kind) {
+        (EntryKind::Dir, _) => decision,
+        (_, EntryKind::Dir) => Decision::Conflict,
+        _ => decision
+    }
+}
+
+fn decide(here: Change, there: Change) -> Decision {
+    match (here, there) {
+        (Change::Unchanged(h) | Change::Changed(h), Change::Unchanged(t) | Change::Changed(t))
+            if h.kind == t.kind && h.hash == t.hash => Decision::Nothing,
+        (Change::Changed(h), Change::Unchanged(t)) => replace(h, t, Decision::Send),
+        (Change::Unchanged(h), Change::Changed(t)) => replace(t, h, Decision::Receive),
+        // Both sides differ from the last sync, or their databases disagree
+        // because they were synced with someone else in between.
+        (Change::Changed(_), Change::Changed(_)) | (Change::Unchanged(_), Change::Unchanged(_)) => Decision::Conflict,
+        // A modified file wins over a deleted one.
+        (Change::Changed(_), Change::Deleted) | (Change::Unchanged(_) | Change::Changed(_), Change::Absent) => Decision::Send,
+        (Change::Deleted, Change::Changed(_)) | (Change::Absent, Change::Unchanged(_) | Change::Changed(_)) => Decision::Receive,
+        (Change::Unchanged(_), Change::Deleted) => Decision::DeleteHere,
+        (Change::Deleted, Change::Unchanged(_)) => Decision::DeleteThere,
+        _ => Decision::Nothing
+    }
+}
+
+#[derive(Default)]
+struct Plan {
+    receive: Vec<Vec<String>>,
+    send: Vec<Vec<String>>,
+    delete_here: Vec<Vec<String>>,
+    delete_there: Vec<Vec<String>>,
+    conflicts: Vec<Vec<String>>
+}
+
+// A directory that still holds something after the sync is not deleted,
+// and goes back to the side that deleted it instead.
+fn keep_dirs(deletions: &mut Vec<Vec<String>>, side: &Side, incoming: &[Vec<String>], restore: &mut Vec<Vec<String>>) {
+    let deleted: HashSet<Vec<String>> = deletions.iter().cloned().collect();
+    let kept: Vec<&Vec<String>> = side.records.keys()
+        .filter(|path| !deleted.contains(*path))
+        .chain(incoming.iter())
+        .collect();
+
+    deletions.retain(|dir| {
+        let in_use = kept.iter().any(|path| path.len() > dir.len() && path.starts_with(dir));
+        if in_use {
+            restore.push(dir.clone());
+        }
+        !in_use
+    });
+}
+
+fn plan_sync(here: &Side, there: &Side) -> Plan {
+    let mut paths: Vec<&Vec<String>> = here.records.keys()
+        .chain(here.deleted.iter())
+        .chain(there.records.keys())
+        .chain(there.deleted.iter())
+        .collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut plan = Plan::default();
+    for path in paths {
+        let list = match decide(here.change(path), there.change(path)) {
+            Decision::Nothing => {
+                continue;
+            }
+            Decision::Send => &mut plan.send,
+            Decision::Receive => &mut plan.receive,
+            Decision::DeleteHere => &mut plan.delete_here,
+            Decision::DeleteThere => &mut plan.delete_there,
+            Decision::Conflict => &mut plan.conflicts
+        };
+        list.push(path.clone());
+    }
+
+    keep_dirs(&mut plan.delete_here, here, &plan.receive, &mut plan.send);
+    keep_dirs(&mut plan.delete_there, there, &plan.send, &mut plan.receive);
+
+    plan
+}
+
+fn select(files: &[FileEntry], paths: &[Vec<String>]) -> Vec<FileEntry> {
+    let paths: HashSet<&Vec<String>> = paths.iter().collect();
+    files.iter()
+        .filter(|f| paths.contains(&f.partial_path))
+        .cloned()
+        .collect()
+}
+
+// The plan is already made, so the transfers themselves only copy files over.
+fn transfer_options(options: &Options) -> Options {
+    Options {
+        delete: false,
+        staging: false,
+        conflict: ConflictPolicy::Overwrite,
+        browse: false,
+        pick: vec![],
+        ..options.clone()
+    }
+}
+
+fn check_options(options: &Options) -> Result<(), GenericError> {
+    if options.dry_run {
+        return Err(new_custom_error("--dry-run is not supported in two-way sync"));
+    }
+
+    Ok(())
+}
+
+// Deletes what was deleted on the other side and saves the new state.
+// Conflicts are left out of the database, so they come up again until
+// both sides agree.
+fn finish(
+    root: &Path,
+    options: &Options,
+    here: &Side,
+    deletions: &[Vec<String>],
+    conflicts: &[Vec<String>]) -> Result<(), GenericError> {
+
+    let mut files = vec![];
+    let mut dirs = vec![];
+    for path in deletions.iter() {
+        match here.records.get(path).map(|r| &r.kind) {
+            Some(EntryKind::Dir) => dirs.push(files::list_to_path(path)),
+            Some(_) => files.push(files::list_to_path(path)),
+            None => { }
+        }
+    }
+    dirs.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+    mirror::delete(&DestDir::open(root)?, Deletions {
+        files,
+        dirs
+    })?;
+
+    let conflicts: HashSet<&Vec<String>> = conflicts.iter().collect();
+    let mut records = vec![];
+    for f in scan(root, &options.scan)? {
+        if conflicts.contains(&f.partial_path) {
+            continue;
+        }
+        records.push(make_record(root, &f, here.records.get(&f.partial_path))?);
+    }
+    save_database(root, records)?;
+
+    let mut conflicts: Vec<PathBuf> = conflicts.iter()
+        .map(|path| files::list_to_path(path))
+        .collect();
+    conflicts.sort();
+    for path in conflicts.iter() {
+        println!("conflict: {} changed on both sides, left as it is", path.display());
+    }
+    println!("{} conflicts", conflicts.len());
+
+    Ok(())
+}
+
+// The side that runs the sync. It compares both trees, decides what goes
+// where, then receives and sends in two regular transfers.
+pub fn drive<E: TcpEndpoint>(
+    mut tcp_endpoint: E,
+    root: PathBuf,
+    options: Options,
+    logger: Sender<LoggerMessage>) -> Result<(), GenericError> {
+    check_options(&options)?;
+
+    let mut stream = tcp_endpoint.get_connection()?;
+    let mut reader = JSONReader::new(stream.try_clone()?);
+
+    let message = DSMessage {
+        message_type: DSMessageType::SyncState
+    };
+    let json = serde_json::to_string(&message)?;
+    stream.write_all(json.as_bytes())?;
+
+    let (files, state) = local_state(&root, &options)?;
+    let here = Side::new(state);
+
+    let state = reader.read_json()?;
+    let state: MessageSyncState = serde_json::from_value(state)?;
+    let there = Side::new(state);
+
+    let plan = plan_sync(&here, &there);
+    println!(
+        "{} to receive, {} to send, {} to delete here, {} to delete on the other side, {} conflicts",
+        plan.receive.len(),
+        plan.send.len(),
+        plan.delete_here.len(),
+        plan.delete_there.len(),
+        plan.conflicts.len());
+
+    // Both sides check the deletions they are about to make against their own limit.
+    mirror::check_max_delete(plan.delete_here.len() + plan.delete_there.len(), options.max_delete)?;
+
+    let message = DSMessage {
+        message_type: DSMessageType::SyncPlan(SyncPlan {
+            send: plan.receive,
+            delete: plan.delete_there,
+            conflicts: plan.conflicts.clone()
+        })
+    };
+    let json = serde_json::to_string(&message)?;
+    stream.write_all(json.as_bytes())?;
+
+    let transfer = transfer_options(&options);
+    let tcp_endpoint = file_receiver::receive_files(tcp_endpoint, root.clone(), transfer.clone(), logger.clone())?;
+    file_sender::send_file_list(tcp_endpoint, root.clone(), select(&files, &plan.send), false, false, logger)?;
+
+    finish(&root, &options, &here, &plan.delete_here, &plan.conflicts)
+}
+
+// The other side of a sync, which does what the driving side decided.
+pub fn respond<E: TcpEndpoint>(
+    mut tcp_endpoint: E,
+    root: PathBuf,
+    options: Options,
+    logger: Sender<LoggerMessage>) -> Result<(), GenericError> {
+    check_options(&options)?;
+
+    let mut stream = tcp_endpoint.wait_for_connection()?;
+    let mut reader = JSONReader::new(stream.try_clone()?);
+
+    let (files, state) = local_state(&root, &options)?;
+    let state_json = serde_json::to_string(&state)?;
+    let here = Side::new(state);
+
+    let plan = loop {
+        let json = reader.read_json()?;
+        let message: DSMessage = serde_json::from_value(json)?;
+
+        match message.message_type {
+            DSMessageType::SyncState => {
+                stream.write_all(state_json.as_bytes())?;
+            }
+            DSMessageType::SyncPlan(plan) => {
+                break plan;
+            }
+            _ => {
+                return Err(new_custom_error("protocol error: expected a sync message"));
+            }
+        }
+    };
+
+    println!(
+        "{} to send, {} to delete, {} conflicts",
+        plan.send.len(),
+        plan.delete.len(),
+        plan.conflicts.len());
+
+    // The plan comes from the other side, which can't be trusted to keep
+    // to the limit of this one.
+    mirror::check_max_delete(plan.delete.len(), options.max_delete)?;
+
+    let tcp_endpoint = file_sender::send_file_list(tcp_endpoint, root.clone(), select(&files, &plan.send), false, false, logger.clone())?;
+    file_receiver::receive_files(tcp_endpoint, root.clone(), transfer_options(&options), logger)?;
+
+    finish(&root, &options, &here, &plan.delete, &plan.conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use files::EntryKind;
+
+    use crate::messages::{MessageSyncState, SyncRecord};
+
+    use super::{decide, plan_sync, Change, Decision, Side};
+
+    fn path(path: &str) -> Vec<String> {
+        path.split('/').map(|n| n.to_string()).collect()
+    }
+
+    fn record(p: &str, kind: EntryKind, hash: &str) -> SyncRecord {
+        SyncRecord {
+            partial_path: path(p),
+            kind,
+            size: 0,
+            mtime: 0,
+            hash: hash.to_string()
+        }
+    }
+
+    fn file(p: &str, hash: &str) -> SyncRecord {
+        record(p, EntryKind::File, hash)
+    }
+
+    fn dir(p: &str) -> SyncRecord {
+        record(p, EntryKind::Dir, "")
+    }
+
+    fn side(records: Vec<SyncRecord>, changed: &[&str], deleted: &[&str]) -> Side {
+        Side::new(MessageSyncState {
+            records,
+            changed: changed.iter().map(|p| path(p)).collect(),
+            deleted: deleted.iter().map(|p| path(p)).collect()
+        })
+    }
+
+    fn sorted(paths: &[Vec<String>]) -> Vec<String> {
+        let mut paths: Vec<String> = paths.iter().map(|p| p.join("/")).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn every_pair_of_changes_is_decided() {
+        let old = file("f", "old");
+        let new = file("f", "new");
+        let other = file("f", "other");
+        let d = dir("f");
+
+        let cases = [
+            (Change::Unchanged(&old), Change::Unchanged(&old), Decision::Nothing),
+            (Change::Changed(&new), Change::Unchanged(&old), Decision::Send),
+            (Change::Unchanged(&old), Change::Changed(&new), Decision::Receive),
+            (Change::Changed(&new), Change::Changed(&other), Decision::Conflict),
+            (Change::Changed(&new), Change::Changed(&new), Decision::Nothing),
+            // The databases disagree about the last synced state.
+            (Change::Unchanged(&old), Change::Unchanged(&new), Decision::Conflict),
+            (Change::Changed(&new), Change::Deleted, Decision::Send),
+            (Change::Deleted, Change::Changed(&new), Decision::Receive),
+            (Change::Unchanged(&old), Change::Deleted, Decision::DeleteHere),
+            (Change::Deleted, Change::Unchanged(&old), Decision::DeleteThere),
+            (Change::Deleted, Change::Deleted, Decision::Nothing),
+            (Change::Changed(&new), Change::Absent, Decision::Send),
+            (Change::Unchanged(&old), Change::Absent, Decision::Send),
+            (Change::Absent, Change::Changed(&new), Decision::Receive),
+            (Change::Absent, Change::Unchanged(&old), Decision::Receive),
+            (Change::Deleted, Change::Absent, Decision::Nothing),
+            (Change::Absent, Change::Deleted, Decision::Nothing),
+            (Change::Absent, Change::Absent, Decision::Nothing),
+            // A file replaced by a directory replaces the file on the other side,
+            // but a directory that is still there is not replaced by a file.
+            (Change::Changed(&d), Change::Unchanged(&old), Decision::Send),
+            (Change::Unchanged(&old), Change::Changed(&d), Decision::Receive),
+            (Change::Unchanged(&d), Change::Changed(&new), Decision::Conflict),
+            (Change::Changed(&new), Change::Unchanged(&d), Decision::Conflict),
+        ];
+        for (i, (here, there, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(decide(here, there), expected, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn plan_sorts_paths_by_decision() {
+        let here = side(
+            vec![
+                file("same", "a"),
+                file("mine", "new"),
+                file("theirs", "old"),
+                file("both", "x"),
+                file("gone_there", "a"),
+                dir("swapped")
+            ],
+            &["mine", "both", "swapped"],
+            &["gone_here"]);
+        let there = side(
+            vec![
+                file("same", "a"),
+                file("mine", "old"),
+                file("theirs", "new"),
+                file("both", "y"),
+                file("gone_here", "a"),
+                file("swapped", "a")
+            ],
+            &["theirs", "both"],
+            &["gone_there"]);
+
+        let plan = plan_sync(&here, &there);
+        assert_eq!(sorted(&plan.send), ["mine", "swapped"]);
+        assert_eq!(sorted(&plan.receive), ["theirs"]);
+        assert_eq!(sorted(&plan.delete_here), ["gone_there"]);
+        assert_eq!(sorted(&plan.delete_there), ["gone_here"]);
+        assert_eq!(sorted(&plan.conflicts), ["both"]);
+    }
+
+    #[test]
+    fn deleted_dirs_with_incoming_files_are_kept() {
+        // The directory is gone here, but the other side added a file to it.
+        let here = side(vec![], &[], &["d", "d/old"]);
+        let there = side(vec![dir("d"), file("d/old", "a"), file("d/new", "b")], &["d/new"], &[]);
+
+        let plan = plan_sync(&here, &there);
+        assert_eq!(sorted(&plan.delete_there), ["d/old"]);
+        assert_eq!(sorted(&plan.receive), ["d", "d/new"]);
+
+        // The same, the other way around.
+        let plan = plan_sync(&there, &here);
+        assert_eq!(sorted(&plan.delete_here), ["d/old"]);
+        assert_eq!(sorted(&plan.send), ["d", "d/new"]);
+
+        // Without anything left in it, the directory is deleted as well.
+        let there = side(vec![dir("d"), file("d/old", "a")], &[], &[]);
+        let plan = plan_sync(&here, &there);
+        assert_eq!(sorted(&plan.delete_there), ["d", "d/old"]);
+        assert!(plan.receive.is_empty());
+    }
+}
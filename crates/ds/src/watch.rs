@@ -0,0 +1,261 @@
+use std::{collections::{HashMap, HashSet}, io::Write, path::{Path, PathBuf}, sync::mpsc::Sender, time::Duration};
+
+use errors::{new_custom_error, GenericError};
+use files::{DestDir, DirWatcher, EntryKind, FileEntry};
+use net::{JSONReader, TcpEndpoint};
+
+use crate::{file_receiver, file_sender, logger::LoggerMessage, messages::{DSMessage, DSMessageType, MessageChanges}, mirror, options::Options};
+
+// Changes are sent once the tree has been quiet for this long, so a burst
+// of writes (or a file that is still being written) goes out in one round.
+static DEBOUNCE: Duration = Duration::from_millis(500);
+
+fn is_modified(old: &FileEntry, new: &FileEntry) -> bool {
+    old.kind != new.kind || old.size != new.size || old.mtime != new.mtime || old.mode != new.mode
+}
+
+// Compares two scans of the tree. A rename shows up as a deletion of the
+// old path and a new file at the new one.
+fn diff(old: &[FileEntry], new: &[FileEntry]) -> (Vec<FileEntry>, Vec<FileEntry>) {
+    let old_entries: HashMap<&Vec<String>, &FileEntry> = old.iter()
+        .map(|f| (&f.partial_path, f))
+        .collect();
+    let new_paths: HashSet<&Vec<String>> = new.iter()
+        .map(|f| &f.partial_path)
+        .collect();
+
+    let mut changed: HashSet<&Vec<String>> = new.iter()
+        .filter(|f| {
+            match old_entries.get(&f.partial_path) {
+                Some(old) => is_modified(old, f),
+                None => true
+            }
+        })
+        .map(|f| &f.partial_path)
+        .collect();
+
+    // A file that is replaced leaves its other names pointing to the old
+    // data, so they are linked again.
+    for f in new.iter() {
+        match &f.kind {
+            EntryKind::HardLink(target) if changed.contains(target) => {
+                changed.insert(&f.partial_path);
+            }
+            _ => { }
+        }
+    }
+
+    let changed = new.iter()
+        .filter(|f| changed.contains(&f.partial_path))
+        .cloned()
+        .collect();
+    let deleted = old.iter()
+        .filter(|f| !new_paths.contains(&f.partial_path))
+        .cloned()
+        .collect();
+
+    (changed, deleted)
+}
+
+// The sending side of a watch. Every round starts when the receiver asks
+// for the next changes, and the changed files go out as a transfer of
+// their own.
+pub fn send_changes<E: TcpEndpoint>(
+    mut tcp_endpoint: E,
+    source: PathBuf,
+    dir: PathBuf,
+    options: Options,
+    mut files: Vec<FileEntry>,
+    watcher: DirWatcher,
+    logger: Sender<LoggerMessage>) -> Result<E, GenericError> {
+
+    watcher.watch_dirs(&dir, &files)?;
+    println!("watching {} for changes", source.display());
+
+    loop {
+        let mut stream = tcp_endpoint.wait_for_connection()?;
+        let mut reader = JSONReader::new(stream.try_clone()?);
+        let message: DSMessage = serde_json::from_value(reader.read_json()?)?;
+        match message.message_type {
+            DSMessageType::WatchChanges => { }
+            _ => {
+                return Err(new_custom_error("protocol error: expected a request for changes"));
+            }
+        }
+
+        let (current, changed, deleted) = loop {
+            watcher.wait_for_changes(DEBOUNCE)?;
+
+            let current = files::scan_source(&source, &options.scan)?;
+            watcher.watch_dirs(&dir, &current)?;
+            let (changed, deleted) = diff(&files, &current);
+            if !changed.is_empty() || !deleted.is_empty() {
+                break (current, changed, deleted);
+            }
+        };
+        println!("{} changed, {} deleted", changed.len(), deleted.len());
+
+        let changes = MessageChanges {
+            deleted
+        };
+        let json = serde_json::to_string(&changes)?;
+        stream.write_all(json.as_bytes())?;
+
+        tcp_endpoint = file_sender::send_file_list(tcp_endpoint, dir.clone(), changed, false, false, logger.clone())?;
+        files = current;
+    }
+}
+
+// Entries that are already gone are not an error, the receiving side may
+// never have had them. The receiver did not ask for deletions, so a round
+// that would delete more than `--max-delete` allows stops the watch.
+fn apply_deletions(root: &Path, deleted: &[FileEntry], options: &Options) -> Result<(), GenericError> {
+    file_receiver::check_file_list(deleted)?;
+    mirror::check_max_delete(deleted.len(), options.max_delete)?;
+    let dest = DestDir::open(root)?;
+
+    let mut deleted: Vec<&FileEntry> = deleted.iter().collect();
+    // Deepest paths first, so directories are empty by the time they are removed.
+    deleted.sort_by_key(|f| std::cmp::Reverse(f.partial_path.len()));
+
+    let mut count = 0;
+    for f in deleted {
+        let path = files::list_to_path(&f.partial_path);
+        if options.dry_run {
+            println!("delete {}", path.display());
+            continue;
+        }
+
+        let res = match f.kind {
+            EntryKind::Dir => dest.remove_dir(&f.partial_path),
+            _ => dest.remove_file(&f.partial_path)
+        };
+        match res {
+            Ok(_) => {
+                count += 1;
+            }
+            Err(e) => {
+                println!("could not remove {}: {}", path.display(), e);
+            }
+        }
+    }
+    if count > 0 {
+        println!("{} deleted", count);
+    }
+
+    Ok(())
+}
+
+// The receiving side of a watch. It runs until the sender goes away.
+pub fn receive_changes<E: TcpEndpoint>(
+    mut tcp_endpoint: E,
+    root: PathBuf,
+    options: Options,
+    logger: Sender<LoggerMessage>) -> Result<E, GenericError> {
+
+    // Each round only lists what changed, so there is nothing to mirror or stage.
+    let options = Options {
+        delete: false,
+        staging: false,
+        ..options
+    };
+    println!("waiting for changes");
+
+    loop {
+        let mut stream = tcp_endpoint.get_connection()?;
+        let message = DSMessage {
+            message_type: DSMessageType::WatchChanges
+        };
+        let json = serde_json::to_string(&message)?;
+        stream.write_all(json.as_bytes())?;
+
+        let mut reader = JSONReader::new(stream.try_clone()?);
+        let changes: MessageChanges = serde_json::from_value(reader.read_json()?)?;
+        // Files that were not picked were never received, so their deletions don't matter.
+        let deleted = match options.pick.is_empty() {
+            true => changes.deleted,
+            false => files::select_files(&root, &changes.deleted, &options.pick)?
+        };
+        apply_deletions(&root, &deleted, &options)?;
+
+        tcp_endpoint = file_receiver::receive_files(tcp_endpoint, root.clone(), options.clone(), logger.clone())?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use files::{EntryKind, FileEntry};
+
+    use super::diff;
+
+    fn entry(path: &str, kind: EntryKind, size: u64, mtime: u64) -> FileEntry {
+        FileEntry {
+            partial_path: path.split('/').map(|n| n.to_string()).collect(),
+            kind,
+            size,
+            mode: 0o644,
+            mtime,
+            atime: 0,
+            xattrs: None
+        }
+    }
+
+    fn file(path: &str, size: u64, mtime: u64) -> FileEntry {
+        entry(path, EntryKind::File, size, mtime)
+    }
+
+    fn paths(entries: &[FileEntry]) -> Vec<String> {
+        let mut paths: Vec<String> = entries.iter().map(|f| f.partial_path.join("/")).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn modified_files_are_changed() {
+        let old = vec![file("a", 1, 1), file("b", 1, 1), file("c", 1, 1)];
+        let mut new = old.clone();
+        new[0].size = 2;
+        new[1].mtime = 2;
+        new[2].mode = 0o600;
+
+        let (changed, deleted) = diff(&old, &new);
+        assert_eq!(paths(&changed), ["a", "b", "c"]);
+        assert!(deleted.is_empty());
+
+        let (changed, deleted) = diff(&old, &old);
+        assert!(changed.is_empty());
+        assert!(deleted.is_empty());
+    }
+
+    #[test]
+    fn deleted_files_are_listed() {
+        let old = vec![entry("d", EntryKind::Dir, 0, 1), file("d/a", 1, 1), file("b", 1, 1)];
+        let new = vec![file("b", 1, 1)];
+
+        let (changed, deleted) = diff(&old, &new);
+        assert!(changed.is_empty());
+        assert_eq!(paths(&deleted), ["d", "d/a"]);
+    }
+
+    #[test]
+    fn renames_are_a_deletion_and_a_new_file() {
+        let old = vec![file("a", 1, 1)];
+        let new = vec![file("b", 1, 1)];
+
+        let (changed, deleted) = diff(&old, &new);
+        assert_eq!(paths(&changed), ["b"]);
+        assert_eq!(paths(&deleted), ["a"]);
+    }
+
+    #[test]
+    fn links_to_a_changed_file_are_linked_again() {
+        let link = entry("link", EntryKind::HardLink(vec!["a".to_string()]), 0, 1);
+        let other = entry("other", EntryKind::HardLink(vec!["b".to_string()]), 0, 1);
+        let old = vec![file("a", 1, 1), file("b", 1, 1), link.clone(), other.clone()];
+        let new = vec![file("a", 2, 2), file("b", 1, 1), link, other];
+
+        let (changed, deleted) = diff(&old, &new);
+        assert_eq!(paths(&changed), ["a", "link"]);
+        assert!(deleted.is_empty());
+    }
+}
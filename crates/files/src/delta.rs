@@ -0,0 +1,329 @@
+use std::{collections::HashMap, fs::File, io::Read};
+
+use errors::{new_custom_error, GenericError};
+
+use crate::file_chunk::{ChunkSource, FileChunk, FILE_CHUNK_SIZE};
+
+pub static DELTA_MIN_SIZE: u64 = 1024 * 1024;
+
+static MIN_BLOCK_SIZE: u64 = 2 * 1024;
+static MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FileSignature {
+    pub block_size: u64,
+    pub blocks: Vec<BlockSignature>
+}
+
+impl FileSignature {
+    // The signature comes from the peer. Blocks of any other size are not
+    // something this side would make, and an empty block would match
+    // forever without moving through the file.
+    pub fn check(&self) -> Result<(), GenericError> {
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return Err(new_custom_error(&format!(
+                "protocol error: invalid delta block size {}",
+                self.block_size)));
+        }
+
+        Ok(())
+    }
+}
+
+// The weak checksum from the rsync paper. It can be moved one byte
+// forward in constant time, so every offset of the file can be tried.
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, x) in block.iter().enumerate() {
+            a = a.wrapping_add(*x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*x as u32));
+        }
+
+        RollingChecksum {
+            a,
+            b,
+            len
+        }
+    }
+
+    fn roll(&mut self, out: u8, add: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(add as u32);
+        self.b = self.b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> String {
+    let hash = blake3::hash(block);
+    hash.to_hex()[..32].to_string()
+}
+
+fn block_size(file_size: u64) -> u64 {
+    let size = (file_size as f64).sqrt() as u64;
+    let size = size.next_multiple_of(1024);
+    size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+// Only full blocks are listed. A shorter block at the end of the file
+// is never matched and the sender transfers it as literal data.
+pub fn compute_signature(mut file: File) -> Result<FileSignature, GenericError> {
+    let size = file.metadata()?.len();
+    let block_size = block_size(size);
+
+    let mut blocks = vec![];
+    let mut buf: Vec<u8> = vec![0; block_size as usize];
+    for _ in 0..size / block_size {
+        file.read_exact(&mut buf)?;
+        blocks.push(BlockSignature {
+            weak: RollingChecksum::new(&buf).digest(),
+            strong: strong_hash(&buf)
+        });
+    }
+
+    Ok(FileSignature {
+        block_size,
+        blocks
+    })
+}
+
+fn literal_chunks(
+    data: &[u8],
+    offset: u64,
+    emit: &mut impl FnMut(FileChunk) -> Result<(), GenericError>) -> Result<(), GenericError> {
+
+    let mut sent = 0;
+    while sent < data.len() {
+        let size = (data.len() - sent).min(FILE_CHUNK_SIZE);
+        let chunk = FileChunk {
+            offset: offset + sent as u64,
+            size: size as u64,
+            source: ChunkSource::Literal,
+            data: data[sent..sent + size].to_vec()
+        };
+        emit(chunk)?;
+        sent += size;
+    }
+
+    Ok(())
+}
+
+// Streams the file as a mix of literal chunks and references to blocks
+// of the receiver's copy described by `signature`.
+pub fn generate_delta(
+    file: &mut impl Read,
+    signature: &FileSignature,
+    mut emit: impl FnMut(FileChunk) -> Result<(), GenericError>) -> Result<(), GenericError> {
+
+    let block_size = signature.block_size as usize;
+
+    let mut blocks = HashMap::<u32, Vec<usize>>::new();
+    for (i, block) in signature.blocks.iter().enumerate() {
+        blocks.entry(block.weak).or_default().push(i);
+    }
+
+    // `buf` holds the data from file offset `buf_offset` on. The pending
+    // literal data is `buf[literal..pos]` and the window is `buf[pos..pos + block_size]`.
+    let mut buf: Vec<u8> = vec![];
+    let mut buf_offset: u64 = 0;
+    let mut literal = 0;
+    let mut pos = 0;
+    let mut checksum: Option<RollingChecksum> = None;
+    let mut eof = false;
+
+    let mut read_buf: Vec<u8> = vec![0; FILE_CHUNK_SIZE];
+
+    loop {
+        if !eof && buf.len() < pos + block_size + 1 {
+            buf.drain(..literal);
+            buf_offset += literal as u64;
+            pos -= literal;
+            literal = 0;
+
+            let read = file.read(&mut read_buf)?;
+            if read == 0 {
+                eof = true;
+            }
+            buf.extend_from_slice(&read_buf[..read]);
+            continue;
+        }
+
+        if buf.len() < pos + block_size {
+            break;
+        }
+
+        let window = &buf[pos..pos + block_size];
+        let weak = match &checksum {
+            Some(checksum) => checksum.digest(),
+            None => {
+                let new_checksum = RollingChecksum::new(window);
+                let weak = new_checksum.digest();
+                checksum = Some(new_checksum);
+                weak
+            }
+        };
+
+        let matched = match blocks.get(&weak) {
+            Some(candidates) => {
+                let strong = strong_hash(window);
+                candidates.iter()
+                    .find(|i| signature.blocks[**i].strong == strong)
+                    .copied()
+            }
+            None => None
+        };
+
+        if let Some(index) = matched {
+            literal_chunks(&buf[literal..pos], buf_offset + literal as u64, &mut emit)?;
+            emit(FileChunk::new_reference(
+                buf_offset + pos as u64,
+                block_size as u64,
+                index as u64 * signature.block_size))?;
+
+            pos += block_size;
+            literal = pos;
+            checksum = None;
+            continue;
+        }
+
+        if pos - literal >= FILE_CHUNK_SIZE {
+            literal_chunks(&buf[literal..pos], buf_offset + literal as u64, &mut emit)?;
+            literal = pos;
+        }
+
+        match (&mut checksum, buf.get(pos + block_size)) {
+            (Some(checksum), Some(add)) => {
+                checksum.roll(buf[pos], *add);
+            }
+            _ => {
+                checksum = None;
+            }
+        }
+        pos += 1;
+    }
+
+    literal_chunks(&buf[literal..], buf_offset + literal as u64, &mut emit)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{self, File}, io::Cursor};
+
+    use crate::file_chunk::{ChunkSource, FileChunk};
+
+    use super::{compute_signature, generate_delta, FileSignature, RollingChecksum, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+
+    // Repeatable data without long runs that would match at many offsets.
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| {
+            x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+            (x >> 24) as u8
+        }).collect()
+    }
+
+    fn signature(basis: &[u8], name: &str) -> FileSignature {
+        let path = std::env::temp_dir().join(format!("ds-delta-{}-{}", std::process::id(), name));
+        fs::write(&path, basis).unwrap();
+        let signature = compute_signature(File::open(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        signature
+    }
+
+    fn delta(file: &[u8], signature: &FileSignature) -> Vec<FileChunk> {
+        let mut chunks = vec![];
+        generate_delta(&mut Cursor::new(file), signature, |chunk| {
+            chunks.push(chunk);
+            Ok(())
+        }).unwrap();
+        chunks
+    }
+
+    fn rebuild(basis: &[u8], chunks: &[FileChunk]) -> Vec<u8> {
+        let mut res = vec![];
+        for chunk in chunks.iter() {
+            assert_eq!(chunk.offset, res.len() as u64);
+            match chunk.source {
+                ChunkSource::Literal => res.extend_from_slice(&chunk.data[..chunk.size as usize]),
+                ChunkSource::Basis(offset) => {
+                    res.extend_from_slice(&basis[offset as usize..(offset + chunk.size) as usize]);
+                }
+                ChunkSource::Hole => panic!("hole in a delta")
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn rolled_checksum_matches_a_new_one() {
+        let data = data(10000, 1);
+        let len = 700;
+        let mut checksum = RollingChecksum::new(&data[..len]);
+        for i in 0..data.len() - len {
+            checksum.roll(data[i], data[i + len]);
+            assert_eq!(checksum.digest(), RollingChecksum::new(&data[i + 1..i + 1 + len]).digest());
+        }
+    }
+
+    #[test]
+    fn identical_file_is_all_references() {
+        let basis = data(300 * 1024, 2);
+        let signature = signature(&basis, "identical");
+        assert_eq!(signature.blocks.len() as u64, basis.len() as u64 / signature.block_size);
+
+        let chunks = delta(&basis, &signature);
+        assert!(chunks.iter().all(|c| matches!(c.source, ChunkSource::Basis(_))));
+        assert_eq!(rebuild(&basis, &chunks), basis);
+    }
+
+    #[test]
+    fn changed_file_is_rebuilt() {
+        let basis = data(300 * 1024, 3);
+        let signature = signature(&basis, "changed");
+
+        let mut file = basis.clone();
+        file.splice(5000..5000, data(123, 4));
+        file.drain(100_000..100_777);
+        file.splice(200_000..200_010, data(3000, 5));
+        file.extend_from_slice(&data(50, 6));
+
+        let chunks = delta(&file, &signature);
+        assert!(chunks.iter().any(|c| matches!(c.source, ChunkSource::Basis(_))));
+        assert!(chunks.iter().any(|c| c.source == ChunkSource::Literal));
+        assert_eq!(rebuild(&basis, &chunks), file);
+    }
+
+    #[test]
+    fn invalid_block_sizes_are_rejected() {
+        let signature = |block_size| FileSignature {
+            block_size,
+            blocks: vec![]
+        };
+        assert!(signature(0).check().is_err());
+        assert!(signature(MIN_BLOCK_SIZE - 1).check().is_err());
+        assert!(signature(MAX_BLOCK_SIZE + 1).check().is_err());
+        assert!(signature(MIN_BLOCK_SIZE).check().is_ok());
+        assert!(signature(MAX_BLOCK_SIZE).check().is_ok());
+    }
+}
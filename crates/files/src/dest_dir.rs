@@ -0,0 +1,340 @@
+use std::{ffi::{CString, OsStr}, fs::{File, Metadata}, io::Error, os::{fd::{AsRawFd, FromRawFd, OwnedFd}, unix::ffi::{OsStrExt, OsStringExt}}, path::{Path, PathBuf}};
+
+use errors::{new_custom_error, GenericError};
+
+use crate::path_encoding::{check_partial_path, decode_name};
+
+// The receiving side of a transfer. Every path is resolved one component
+// at a time from an open handle of the destination root, and symbolic
+// links are never followed on the way, so a link that is already in the
+// tree (or is planted while `ds` runs) can't redirect a write outside of it.
+pub struct DestDir {
+    root: OwnedFd
+}
+
+pub(crate) fn cvt(res: libc::c_int) -> Result<libc::c_int, Error> {
+    match res {
+        -1 => Err(Error::last_os_error()),
+        x => Ok(x)
+    }
+}
+
+fn to_cstring(name: &OsStr) -> Result<CString, GenericError> {
+    CString::new(name.as_bytes())
+        .map_err(|_| new_custom_error("NUL byte in file name"))
+}
+
+fn open_at(dir: &OwnedFd, name: &CString, flags: libc::c_int) -> Result<OwnedFd, Error> {
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = cvt(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, 0o666) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl DestDir {
+    pub fn open(root: &Path) -> Result<Self, GenericError> {
+        let root = File::open(root)?;
+        if !root.metadata()?.is_dir() {
+            return Err(new_custom_error("destination is not a directory"));
+        }
+
+        Ok(DestDir {
+            root: root.into()
+        })
+    }
+
+    // Opens the directory that holds the last component of `partial_path`.
+    // Missing directories on the way are created if `create` is set.
+    fn parent(&self, partial_path: &[String], create: bool) -> Result<(OwnedFd, CString), GenericError> {
+        check_partial_path(partial_path)?;
+        let (name, dirs) = partial_path.split_last()
+            .ok_or(new_custom_error("empty path"))?;
+
+        let mut dir = self.root.try_clone()?;
+        for d in dirs {
+            let d = to_cstring(&decode_name(d))?;
+            let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+            dir = match open_at(&dir, &d, flags) {
+                Ok(fd) => fd,
+                Err(e) if create && e.raw_os_error() == Some(libc::ENOENT) => {
+                    let res = cvt(unsafe { libc::mkdirat(dir.as_raw_fd(), d.as_ptr(), 0o777) });
+                    match res {
+                        Err(e) if e.raw_os_error() != Some(libc::EEXIST) => {
+                            return Err(e.into());
+                        }
+                        _ => { }
+                    }
+                    open_at(&dir, &d, flags)?
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            };
+        }
+
+        Ok((dir, to_cstring(&decode_name(name))?))
+    }
+
+    // Information about the entry itself, even if it is a symbolic link.
+    pub fn metadata(&self, partial_path: &[String]) -> Result<Metadata, GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        let fd = open_at(&dir, &name, libc::O_PATH)?;
+        Ok(File::from(fd).metadata()?)
+    }
+
+    // Opens a regular file for reading. The open doesn't wait for a writer
+    // if a FIFO is in its place, and anything but a file is refused.
+    pub fn open_file(&self, partial_path: &[String]) -> Result<File, GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        let file = File::from(open_at(&dir, &name, libc::O_RDONLY | libc::O_NONBLOCK)?);
+        if !file.metadata()?.is_file() {
+            return Err(new_custom_error("not a regular file"));
+        }
+
+        Ok(file)
+    }
+
+    pub fn open_dir(&self, partial_path: &[String]) -> Result<File, GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        Ok(open_at(&dir, &name, libc::O_RDONLY | libc::O_DIRECTORY)?.into())
+    }
+
+    // Changes the permission bits without opening the file for reading or
+    // writing, so it works whatever its current mode is. Symbolic links
+    // have no mode of their own and are refused.
+    pub fn set_mode(&self, partial_path: &[String], mode: u32) -> Result<(), GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        let fd = open_at(&dir, &name, libc::O_PATH)?;
+        if File::from(fd.try_clone()?).metadata()?.is_symlink() {
+            return Err(new_custom_error("can't change the mode of a symbolic link"));
+        }
+
+        let path = to_cstring(OsStr::new(&format!("/proc/self/fd/{}", fd.as_raw_fd())))?;
+        cvt(unsafe { libc::chmod(path.as_ptr(), mode as libc::mode_t) })?;
+        Ok(())
+    }
+
+    // Opens an existing file for writing, without truncating it.
+    pub fn open_file_write(&self, partial_path: &[String]) -> Result<File, GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        Ok(open_at(&dir, &name, libc::O_WRONLY)?.into())
+    }
+
+    // Creates or truncates a file. A symbolic link in its place is
+    // replaced rather than written through.
+    pub fn create_file(&self, partial_path: &[String]) -> Result<File, GenericError> {
+        let (dir, name) = self.parent(partial_path, true)?;
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+        let fd = match open_at(&dir, &name, flags) {
+            Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+                cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })?;
+                open_at(&dir, &name, flags)?
+            }
+            res => res?
+        };
+
+        Ok(fd.into())
+    }
+
+    // Returns false if the directory was already there. Anything else in
+    // its place is removed first.
+    pub fn create_dir(&self, partial_path: &[String]) -> Result<bool, GenericError> {
+        let (dir, name) = self.parent(partial_path, true)?;
+        if let Ok(fd) = open_at(&dir, &name, libc::O_PATH) {
+            if File::from(fd).metadata()?.is_dir() {
+                return Ok(false);
+            }
+            cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })?;
+        }
+
+        cvt(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o777) })?;
+        Ok(true)
+    }
+
+    pub fn symlink(&self, target: &OsStr, partial_path: &[String]) -> Result<(), GenericError> {
+        let (dir, name) = self.parent(partial_path, true)?;
+        let target = to_cstring(target)?;
+        cvt(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })?;
+        Ok(())
+    }
+
+    pub fn read_link(&self, partial_path: &[String]) -> Result<PathBuf, GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        let mut buf: Vec<u8> = vec![0; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::readlinkat(dir.as_raw_fd(), name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+        };
+        if len < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        buf.truncate(len as usize);
+
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(buf)))
+    }
+
+    pub fn hard_link(&self, target: &[String], partial_path: &[String]) -> Result<(), GenericError> {
+        let (target_dir, target_name) = self.parent(target, false)?;
+        let (dir, name) = self.parent(partial_path, true)?;
+        cvt(unsafe {
+            libc::linkat(target_dir.as_raw_fd(), target_name.as_ptr(), dir.as_raw_fd(), name.as_ptr(), 0)
+        })?;
+        Ok(())
+    }
+
+    // Links an entry of another tree into this one, at the same path.
+    pub fn hard_link_from(&self, source: &DestDir, partial_path: &[String]) -> Result<(), GenericError> {
+        let (source_dir, source_name) = source.parent(partial_path, false)?;
+        let (dir, name) = self.parent(partial_path, true)?;
+        cvt(unsafe {
+            libc::linkat(source_dir.as_raw_fd(), source_name.as_ptr(), dir.as_raw_fd(), name.as_ptr(), 0)
+        })?;
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &[String], to: &[String]) -> Result<(), GenericError> {
+        let (from_dir, from_name) = self.parent(from, false)?;
+        let (to_dir, to_name) = self.parent(to, true)?;
+        cvt(unsafe {
+            libc::renameat(from_dir.as_raw_fd(), from_name.as_ptr(), to_dir.as_raw_fd(), to_name.as_ptr())
+        })?;
+        Ok(())
+    }
+
+    pub fn remove_file(&self, partial_path: &[String]) -> Result<(), GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })?;
+        Ok(())
+    }
+
+    pub fn remove_dir(&self, partial_path: &[String]) -> Result<(), GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) })?;
+        Ok(())
+    }
+
+    // Calls `f` with a path that reaches the entry through the already
+    // opened parent directory, for APIs that only take paths.
+    pub fn with_path<T>(&self, partial_path: &[String], f: impl FnOnce(&Path) -> T) -> Result<T, GenericError> {
+        let (dir, name) = self.parent(partial_path, false)?;
+        let mut path = PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()));
+        path.push(OsStr::from_bytes(name.as_bytes()));
+        Ok(f(&path))
+    }
+}
+
+// Swaps two directories in one step, so no one ever sees a mix of both.
+pub fn exchange_dirs(a: &Path, b: &Path) -> Result<(), GenericError> {
+    let a = to_cstring(a.as_os_str())?;
+    let b = to_cstring(b.as_os_str())?;
+    cvt(unsafe {
+        libc::renameat2(libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), libc::RENAME_EXCHANGE)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::{symlink, PermissionsExt}, path::PathBuf};
+
+    use super::DestDir;
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("ds-dest-dir-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("root")).unwrap();
+            fs::create_dir_all(path.join("outside")).unwrap();
+            TestDir(path)
+        }
+
+        fn root(&self) -> PathBuf {
+            self.0.join("root")
+        }
+
+        fn outside(&self) -> PathBuf {
+            self.0.join("outside")
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn path(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn symlinked_parent_is_refused() {
+        let dir = TestDir::new("parent");
+        symlink(dir.outside(), dir.root().join("link")).unwrap();
+        fs::write(dir.outside().join("file"), b"outside").unwrap();
+
+        let dest = DestDir::open(&dir.root()).unwrap();
+        assert!(dest.open_file(&path(&["link", "file"])).is_err());
+        assert!(dest.create_file(&path(&["link", "new"])).is_err());
+        assert!(dest.create_dir(&path(&["link", "dir"])).is_err());
+        assert!(!dir.outside().join("new").exists());
+        assert!(!dir.outside().join("dir").exists());
+    }
+
+    #[test]
+    fn symlinked_file_is_refused() {
+        let dir = TestDir::new("file");
+        let target = dir.outside().join("file");
+        fs::write(&target, b"outside").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        symlink(&target, dir.root().join("link")).unwrap();
+
+        let dest = DestDir::open(&dir.root()).unwrap();
+        assert!(dest.open_file(&path(&["link"])).is_err());
+        assert!(dest.open_file_write(&path(&["link"])).is_err());
+        assert!(dest.set_mode(&path(&["link"]), 0o600).is_err());
+
+        // A new file replaces the link instead of writing through it.
+        dest.create_file(&path(&["link"])).unwrap();
+        assert!(!fs::symlink_metadata(dir.root().join("link")).unwrap().is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"outside");
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o7777, 0o644);
+    }
+
+    #[test]
+    fn parent_components_are_refused() {
+        let dir = TestDir::new("dotdot");
+        fs::create_dir(dir.root().join("sub")).unwrap();
+        fs::write(dir.outside().join("file"), b"outside").unwrap();
+
+        let dest = DestDir::open(&dir.root()).unwrap();
+        assert!(dest.open_file(&path(&["..", "outside", "file"])).is_err());
+        assert!(dest.create_file(&path(&["sub", "..", "..", "new"])).is_err());
+        assert!(dest.metadata(&path(&[".."])).is_err());
+        assert!(!dir.0.join("new").exists());
+    }
+
+    #[test]
+    fn only_regular_files_are_opened() {
+        let dir = TestDir::new("fifo");
+        let fifo = std::ffi::CString::new(dir.root().join("fifo").into_os_string().into_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        fs::create_dir(dir.root().join("dir")).unwrap();
+
+        let dest = DestDir::open(&dir.root()).unwrap();
+        assert!(dest.open_file(&path(&["fifo"])).is_err());
+        assert!(dest.open_file(&path(&["dir"])).is_err());
+    }
+
+    #[test]
+    fn mode_is_set_without_read_access() {
+        let dir = TestDir::new("mode");
+        let file = dir.root().join("file");
+        fs::write(&file, b"data").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let dest = DestDir::open(&dir.root()).unwrap();
+        dest.set_mode(&path(&["file"]), 0o640).unwrap();
+        assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o7777, 0o640);
+    }
+}
@@ -1,43 +1,176 @@
-use std::{collections::VecDeque, path::{Path, PathBuf}};
+use std::{collections::{HashMap, VecDeque}, os::unix::fs::MetadataExt, path::{Path, PathBuf}, time::{Duration, SystemTime}};
 
-use errors::GenericError;
+use errors::{new_custom_error, GenericError};
+
+use crate::{path_encoding::{decode_name, encode_name}, file_xattrs::{read_xattrs, ExtendedAttribute}, scan_filter::{LinkMode, ScanFilter, ScanOptions}};
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum EntryKind {
+    File,
+    Dir,
+    // The link target, encoded like the names in `partial_path`.
+    Symlink(String),
+    // Another name of the file at this path, which is listed before it.
+    HardLink(Vec<String>)
+}
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct FileEntry {
     pub partial_path: Vec<String>,
-    pub size: u64
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+    pub atime: u64,
+    pub xattrs: Option<Vec<ExtendedAttribute>>
 }
 
-pub fn get_files_in_dir(dir: &std::path::Path) -> Result<Vec<FileEntry>, GenericError> {
-    let mut to_process = VecDeque::<std::path::PathBuf>::new();
-    to_process.push_back(dir.to_path_buf());
+impl FileEntry {
+    fn new(partial_path: Vec<String>, kind: EntryKind, meta: &std::fs::Metadata) -> Result<Self, GenericError> {
+        let size = match kind {
+            EntryKind::File => meta.len(),
+            _ => 0
+        };
+
+        Ok(FileEntry {
+            partial_path,
+            kind,
+            size,
+            mode: meta.mode() & 0o7777,
+            mtime: to_timestamp(meta.modified()?),
+            atime: to_timestamp(meta.accessed()?),
+            xattrs: None
+        })
+    }
+}
+
+// Timestamps travel as nanoseconds since the Unix epoch.
+pub fn to_timestamp(time: SystemTime) -> u64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(dur) => dur.as_nanos() as u64,
+        Err(_) => 0
+    }
+}
+
+pub fn from_timestamp(stamp: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_nanos(stamp)
+}
+
+struct ScanDir {
+    path: PathBuf,
+    filter: ScanFilter,
+    // Device and inode of this directory and the ones above it, so that
+    // following a symbolic link back up the tree does not loop forever.
+    ancestors: Vec<(u64, u64)>
+}
+
+pub fn get_files_in_dir(dir: &std::path::Path, options: &ScanOptions) -> Result<Vec<FileEntry>, GenericError> {
+    let root_meta = std::fs::metadata(dir)?;
+
+    let mut to_process = VecDeque::<ScanDir>::new();
+    to_process.push_back(ScanDir {
+        path: dir.to_path_buf(),
+        filter: ScanFilter::new(dir, options)?,
+        ancestors: vec![(root_meta.dev(), root_meta.ino())]
+    });
 
     let mut files = vec![];
+    let mut inodes = HashMap::<(u64, u64), Vec<String>>::new();
 
     while let Some(cur) = to_process.pop_front() {
-        let read_dir = std::fs::read_dir(cur)?;
+        let filter = cur.filter.enter_dir(&cur.path)?;
+        let read_dir = std::fs::read_dir(&cur.path)?;
 
         for item in read_dir {
             let entry = item?;
-            let meta = entry.metadata()?;
+            let path = entry.path();
+            let mut meta = entry.metadata()?;
+
+            if meta.is_symlink() {
+                match options.links {
+                    LinkMode::Skip => {
+                        continue;
+                    }
+                    LinkMode::Preserve => {
+                        if filter.is_excluded(&path, false) {
+                            continue;
+                        }
+
+                        let target = std::fs::read_link(&path)?;
+                        let mut file = FileEntry::new(
+                            path_to_list(path.strip_prefix(dir)?),
+                            EntryKind::Symlink(encode_name(target.as_os_str())),
+                            &meta)?;
+                        if options.xattrs {
+                            file.xattrs = Some(read_xattrs(&path));
+                        }
+
+                        files.push(file);
+                        continue;
+                    }
+                    LinkMode::Follow => {
+                        // Dangling links have nothing to follow.
+                        meta = match std::fs::metadata(&path) {
+                            Ok(meta) => meta,
+                            Err(_) => {
+                                continue;
+                            }
+                        };
+                    }
+                }
+            }
+
+            // Excluded directories are dropped here, so their contents are never read.
+            if filter.is_excluded(&path, meta.is_dir()) {
+                continue;
+            }
 
             if meta.is_file() {
-                let path = entry.path();
-                let path = path.strip_prefix(dir)?;
+                let path_list = path_to_list(path.strip_prefix(dir)?);
+
+                // Only the first name of a file carries its data, the others link to it.
+                if meta.nlink() > 1 {
+                    let id = (meta.dev(), meta.ino());
+                    match inodes.get(&id) {
+                        Some(first) => {
+                            let file = FileEntry::new(path_list, EntryKind::HardLink(first.clone()), &meta)?;
+                            files.push(file);
+                            continue;
+                        }
+                        None => {
+                            inodes.insert(id, path_list.clone());
+                        }
+                    }
+                }
 
-                let path_list = path_to_list(&path);
-                let file = FileEntry {
-                    partial_path: path_list,
-                    size: meta.len() as u64
-                };
+                let mut file = FileEntry::new(path_list, EntryKind::File, &meta)?;
+                if options.xattrs {
+                    file.xattrs = Some(read_xattrs(&path));
+                }
 
                 files.push(file);
                 continue;
             }
 
             if meta.is_dir() {
-                let path = entry.path();
-                to_process.push_back(path);
+                let id = (meta.dev(), meta.ino());
+                if cur.ancestors.contains(&id) {
+                    continue;
+                }
+
+                let mut file = FileEntry::new(path_to_list(path.strip_prefix(dir)?), EntryKind::Dir, &meta)?;
+                if options.xattrs {
+                    file.xattrs = Some(read_xattrs(&path));
+                }
+                files.push(file);
+
+                let mut ancestors = cur.ancestors.clone();
+                ancestors.push(id);
+                to_process.push_back(ScanDir {
+                    path,
+                    filter: filter.clone(),
+                    ancestors
+                });
             }
         }
     }
@@ -45,6 +178,38 @@ pub fn get_files_in_dir(dir: &std::path::Path) -> Result<Vec<FileEntry>, Generic
     Ok(files)
 }
 
+// The directory the paths of a scan of `source` are relative to: the
+// source itself, or the directory that holds it if it is a single file.
+pub fn source_root(source: &Path) -> Result<PathBuf, GenericError> {
+    match std::fs::metadata(source)?.is_dir() {
+        true => Ok(source.to_path_buf()),
+        false => source.parent()
+            .map(|p| p.to_path_buf())
+            .ok_or(new_custom_error("source has no parent directory"))
+    }
+}
+
+// Scans a directory, or lists a single file under its own name. A file
+// that was asked for by name is not filtered.
+pub fn scan_source(source: &Path, options: &ScanOptions) -> Result<Vec<FileEntry>, GenericError> {
+    let meta = std::fs::metadata(source)?;
+    if meta.is_dir() {
+        return get_files_in_dir(source, options);
+    }
+    if !meta.is_file() {
+        return Err(new_custom_error("source is neither a file nor a directory"));
+    }
+
+    let name = source.file_name()
+        .ok_or(new_custom_error("source has no file name"))?;
+    let mut file = FileEntry::new(vec![encode_name(name)], EntryKind::File, &meta)?;
+    if options.xattrs {
+        file.xattrs = Some(read_xattrs(source));
+    }
+
+    Ok(vec![file])
+}
+
 pub fn path_to_list(path: &Path) -> Vec<String> {
     let mut res = vec![];
     let mut cur = Some(path);
@@ -57,9 +222,7 @@ pub fn path_to_list(path: &Path) -> Vec<String> {
             Some(path) => {
                 let name = path.file_name();
                 if let Some(name) = name {
-                    if let Some(name) = name.to_str() {
-                        res.insert(0, name.to_string());
-                    }
+                    res.insert(0, encode_name(name));
                 }
                 let parent = path.parent();
                 cur = parent;
@@ -74,8 +237,7 @@ pub fn list_to_path(l: &Vec<String>) -> PathBuf {
     let mut p = PathBuf::new();
 
     for name in l.iter() {
-        let tmp: PathBuf = name.into();
-        p = p.join(tmp);
+        p = p.join(decode_name(name));
     }
 
     p
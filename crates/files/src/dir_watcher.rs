@@ -0,0 +1,92 @@
+use std::{ffi::CString, io::{Error, ErrorKind}, os::{fd::{AsRawFd, FromRawFd, OwnedFd}, unix::ffi::OsStrExt}, path::Path, time::Duration};
+
+use errors::GenericError;
+
+use crate::{dest_dir::cvt, list_to_path, EntryKind, FileEntry};
+
+static WATCH_MASK: u32 = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MODIFY | libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_ATTRIB | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF | libc::IN_ONLYDIR;
+
+// Tells when something changes in a tree. inotify only watches single
+// directories, so every directory of the tree gets a watch of its own.
+// The events themselves are not looked at: the caller scans the tree
+// again to find out what changed.
+pub struct DirWatcher {
+    fd: OwnedFd
+}
+
+impl DirWatcher {
+    pub fn new(root: &Path) -> Result<Self, GenericError> {
+        let fd = cvt(unsafe { libc::inotify_init1(libc::IN_CLOEXEC) })?;
+        let watcher = DirWatcher {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) }
+        };
+        watcher.add_watch(root)?;
+
+        Ok(watcher)
+    }
+
+    fn add_watch(&self, path: &Path) -> Result<(), Error> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+        cvt(unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) })?;
+        Ok(())
+    }
+
+    // Watching a directory again is harmless, so this is called with the
+    // whole tree after every scan. Directories that are already gone are
+    // skipped, the next scan won't list them.
+    pub fn watch_dirs(&self, root: &Path, files: &[FileEntry]) -> Result<(), GenericError> {
+        for f in files.iter().filter(|f| f.kind == EntryKind::Dir) {
+            match self.add_watch(&root.join(list_to_path(&f.partial_path))) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) || e.raw_os_error() == Some(libc::ENOTDIR) => { }
+                res => res?
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns false if the poll timed out.
+    fn read_events(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<bool, GenericError> {
+        if let Some(timeout) = timeout {
+            let mut pfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0
+            };
+            match cvt(unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) }) {
+                Ok(0) => {
+                    return Ok(false);
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => {
+                    return Ok(true);
+                }
+                res => {
+                    res?;
+                }
+            }
+        }
+
+        let len = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if len < 0 {
+            let e = Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINTR) {
+                return Err(e.into());
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Blocks until something changes, then waits for the tree to be quiet
+    // for `quiet`, so a burst of changes is reported only once.
+    pub fn wait_for_changes(&self, quiet: Duration) -> Result<(), GenericError> {
+        let mut buf = vec![0; 64 * 1024];
+        self.read_events(&mut buf, None)?;
+        while self.read_events(&mut buf, Some(quiet))? { }
+
+        Ok(())
+    }
+}
@@ -1,10 +1,44 @@
-static FILE_CHUNK_SIZE: usize = 8 * 1024 * 1024;
-pub static FILE_CHUNK_MAX_SIZE: usize = 2 * size_of::<u64>() + FILE_CHUNK_SIZE;
+pub static FILE_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+pub static FILE_CHUNK_HEADER_SIZE: usize = 3 * size_of::<u64>();
+pub static FILE_CHUNK_MAX_SIZE: usize = FILE_CHUNK_HEADER_SIZE + FILE_CHUNK_SIZE;
+
+static LITERAL_SOURCE: u64 = u64::MAX;
+static HOLE_SOURCE: u64 = u64::MAX - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkSource {
+    // The chunk carries its own data.
+    Literal,
+    // No data, the bytes are copied from this offset of the receiver's
+    // existing copy of the file.
+    Basis(u64),
+    // No data, the range is a hole in a sparse file.
+    Hole
+}
+
+impl ChunkSource {
+    fn to_header(self) -> u64 {
+        match self {
+            ChunkSource::Literal => LITERAL_SOURCE,
+            ChunkSource::Hole => HOLE_SOURCE,
+            ChunkSource::Basis(offset) => offset
+        }
+    }
+
+    fn from_header(value: u64) -> Self {
+        match value {
+            x if x == LITERAL_SOURCE => ChunkSource::Literal,
+            x if x == HOLE_SOURCE => ChunkSource::Hole,
+            x => ChunkSource::Basis(x)
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct FileChunk {
     pub offset: u64,
     pub size: u64,
+    pub source: ChunkSource,
     pub data: Vec<u8>
 }
 
@@ -16,14 +50,55 @@ impl FileChunk {
         FileChunk {
             offset: 0,
             size: 0,
+            source: ChunkSource::Literal,
             data
         }
     }
 
+    pub fn new_reference(offset: u64, size: u64, source: u64) -> Self {
+        FileChunk {
+            offset,
+            size,
+            source: ChunkSource::Basis(source),
+            data: vec![]
+        }
+    }
+
+    pub fn new_hole(offset: u64, size: u64) -> Self {
+        FileChunk {
+            offset,
+            size,
+            source: ChunkSource::Hole,
+            data: vec![]
+        }
+    }
+
+    pub fn data_size(&self) -> u64 {
+        match self.source {
+            ChunkSource::Literal => self.size,
+            _ => 0
+        }
+    }
+
+    pub fn data_size_from_header(header: &[u8]) -> u64 {
+        let u64_size = size_of::<u64>();
+
+        let mut size_bytes: [u8; 8] = [0; 8];
+        size_bytes.copy_from_slice(&header[u64_size..2 * u64_size]);
+        let size = u64::from_be_bytes(size_bytes);
+
+        let mut source_bytes: [u8; 8] = [0; 8];
+        source_bytes.copy_from_slice(&header[2 * u64_size..3 * u64_size]);
+        match ChunkSource::from_header(u64::from_be_bytes(source_bytes)) {
+            ChunkSource::Literal => size,
+            _ => 0
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let u64_size = size_of::<u64>();
-        let data_len = (self.size as usize) * size_of::<u8>(); 
-        let len = 2 * u64_size + data_len;
+        let data_len = (self.data_size() as usize) * size_of::<u8>(); 
+        let len = FILE_CHUNK_HEADER_SIZE + data_len;
         let mut res = Vec::with_capacity(len);
         res.resize(len, 0);
 
@@ -40,7 +115,13 @@ impl FileChunk {
         }
 
         {
-            let data_bytes = &mut res[2 * u64_size..];
+            let source_bytes = &mut res[2 * u64_size..3 * u64_size];
+            let source = self.source.to_header().to_be_bytes();
+            source_bytes.copy_from_slice(&source);
+        }
+
+        {
+            let data_bytes = &mut res[FILE_CHUNK_HEADER_SIZE..];
             data_bytes.copy_from_slice(&self.data[..data_len]);
         }
 
@@ -63,15 +144,32 @@ impl FileChunk {
             u64::from_be_bytes(size_bytes)
         };
 
+        let source = {
+            let mut source_bytes: [u8; 8] = [0; 8];
+            source_bytes.copy_from_slice(&bytes[2 * u64_size..3 * u64_size]);
+            ChunkSource::from_header(u64::from_be_bytes(source_bytes))
+        };
+
+        match source {
+            ChunkSource::Basis(source) => {
+                return FileChunk::new_reference(offset, size, source);
+            }
+            ChunkSource::Hole => {
+                return FileChunk::new_hole(offset, size);
+            }
+            ChunkSource::Literal => { }
+        }
+
         let mut data: Vec<u8> = vec![0; FILE_CHUNK_SIZE];
         {
             let tmp = &mut data[0..size as usize];
-            tmp.copy_from_slice(&bytes[2 * u64_size.. 2 * u64_size + size as usize * u8_size]);
+            tmp.copy_from_slice(&bytes[FILE_CHUNK_HEADER_SIZE..FILE_CHUNK_HEADER_SIZE + size as usize * u8_size]);
         }
 
         FileChunk {
             offset,
             size,
+            source,
             data
         }
     }
@@ -0,0 +1,14 @@
+use std::{fs::File, io::Read};
+
+use errors::{new_custom_error, GenericError};
+
+pub fn hash_file_prefix(file: File, len: u64) -> Result<String, GenericError> {
+    let mut hasher = blake3::Hasher::new();
+
+    let hashed = std::io::copy(&mut file.take(len), &mut hasher)?;
+    if hashed != len {
+        return Err(new_custom_error("file shorter than prefix"));
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
@@ -0,0 +1,17 @@
+use std::{fs::{File, FileTimes, Permissions}, os::unix::fs::PermissionsExt};
+
+use errors::GenericError;
+
+use crate::{dir_scanner::from_timestamp, FileEntry};
+
+// Gives an open file or directory the permissions and times of `entry`.
+pub fn apply_metadata(file: &File, entry: &FileEntry) -> Result<(), GenericError> {
+    file.set_permissions(Permissions::from_mode(entry.mode))?;
+
+    let times = FileTimes::new()
+        .set_modified(from_timestamp(entry.mtime))
+        .set_accessed(from_timestamp(entry.atime));
+    file.set_times(times)?;
+
+    Ok(())
+}
@@ -1,88 +1,170 @@
-use std::{io::Read, sync::{mpsc::{channel, Receiver, Sender}, Mutex}};
+use std::{fs::File, io::{Read, Seek, SeekFrom}, os::fd::AsRawFd, sync::{atomic::{AtomicBool, Ordering}, mpsc::{channel, Receiver, Sender}, Arc, Mutex}};
 
-use errors::GenericError;
+use errors::{new_custom_error, GenericError};
 
-use crate::{file_chunk::{FileChunk, FILE_CHUNK_MAX_SIZE}, file_reader_manager::FileReaderMessage};
+use crate::{delta::{self, FileSignature}, file_chunk::FileChunk};
 
 pub struct FileReader {
     pub name: String,
-    pub chunk_receiver: Mutex<Receiver<Option<FileChunk>>>,
+    pub chunk_receiver: Mutex<Receiver<ReadResult>>,
     pub size: u64,
-    chunk_sender: Sender<Option<FileChunk>>,
-    slot_sender: Sender<()>
+    pub offset: u64,
+    chunk_sender: Sender<ReadResult>,
+    slot_sender: Sender<()>,
+    finished: Arc<AtomicBool>
 }
 
 static MAX_CHUNKS: u8 = 10;
 
+// The next chunk, `None` at the end of the file, or why the file could
+// not be read. The end and the error are passed on to every stream.
+type ReadResult = Result<Option<FileChunk>, String>;
+
+// Finds the first range of data at or after `offset`, skipping holes.
+// Filesystems that can't tell report the whole rest of the file as data.
+fn next_data_range(file: &File, offset: u64, size: u64) -> (u64, u64) {
+    let fd = file.as_raw_fd();
+
+    let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+    if start < 0 {
+        return match std::io::Error::last_os_error().raw_os_error() {
+            // No data left, the rest of the file is a hole.
+            Some(libc::ENXIO) => (size, size),
+            _ => (offset, size)
+        };
+    }
+    let start = (start as u64).min(size);
+
+    let end = unsafe { libc::lseek(fd, start as libc::off_t, libc::SEEK_HOLE) };
+    if end < 0 {
+        return (start, size);
+    }
+
+    (start, (end as u64).min(size))
+}
+
+// Which part of the file is sent, and how.
+pub struct ReadPlan {
+    pub offset: u64,
+    // With a signature of the receiver's copy, the file is sent as a delta.
+    pub signature: Option<FileSignature>
+}
+
 impl FileReader {
     pub fn new(
-        id: u32,
         name: String,
         file: std::path::PathBuf,
         size: u64,
-        pool: &thread_pool::ThreadPool,
-        finish_channel: Sender<FileReaderMessage>) -> FileReader {
+        plan: ReadPlan,
+        pool: &thread_pool::ThreadPool) -> FileReader {
+        let ReadPlan { offset, signature } = plan;
         let (slot_sender, slot_receiver) = channel::<()>();
         let (chunk_sender, chunk_receiver) = channel();
 
         let chunk_sender_clone = chunk_sender.clone();
+        let finished = Arc::new(AtomicBool::new(false));
+        let finished_clone = Arc::clone(&finished);
+
+        // The slots are handed out up front, since a short file can be read
+        // to the end before this function returns.
+        for _ in 0..MAX_CHUNKS {
+            slot_sender.send(()).unwrap();
+        }
 
         pool.execute(move || -> Result<(), GenericError> {
             let chunk_sender = chunk_sender_clone;
-
-            let mut file = std::fs::File::open(&file)?;
-            let meta = file.metadata()?;
-            let size = meta.len();
-
-            let mut read = 0;
-
-            let mut buf: Vec<u8> = vec![0; FILE_CHUNK_MAX_SIZE];
-            while read < size {
-                slot_receiver.recv()?;
-
-                let data = &mut buf[2 * size_of::<u64>()..];
-                let bytes_read = file.read(data)?;
-                let mut chunk = FileChunk::new();
-                chunk.offset = read;
-                chunk.size = bytes_read as u64;
-                chunk.data.copy_from_slice(data);
-
-                read += chunk.size;
-                chunk_sender.send(Some(chunk))?;
+            let read_file = || -> Result<(), GenericError> {
+                let mut file = std::fs::File::open(&file)?;
+                let meta = file.metadata()?;
+                let size = meta.len();
+
+                match signature {
+                    Some(signature) => {
+                        delta::generate_delta(&mut file, &signature, |chunk| {
+                            slot_receiver.recv()?;
+                            chunk_sender.send(Ok(Some(chunk)))?;
+                            Ok(())
+                        })?;
+                    }
+                    None => {
+                        let mut read = offset;
+
+                        while read < size {
+                            let (data_start, data_end) = next_data_range(&file, read, size);
+                            if data_start > read {
+                                slot_receiver.recv()?;
+                                chunk_sender.send(Ok(Some(FileChunk::new_hole(read, data_start - read))))?;
+                                read = data_start;
+                            }
+
+                            file.seek(SeekFrom::Start(read))?;
+                            while read < data_end {
+                                slot_receiver.recv()?;
+
+                                let mut chunk = FileChunk::new();
+                                let len = chunk.data.len().min((data_end - read) as usize);
+                                let bytes_read = file.read(&mut chunk.data[..len])?;
+                                if bytes_read == 0 {
+                                    return Err(new_custom_error("file shrank while reading"));
+                                }
+                                chunk.offset = read;
+                                chunk.size = bytes_read as u64;
+
+                                read += chunk.size;
+                                chunk_sender.send(Ok(Some(chunk)))?;
+                            }
+                        }
+                    }
+                }
+                chunk_sender.send(Ok(None))?;
+
+                finished_clone.store(true, Ordering::Release);
+                Ok(())
+            };
+
+            if let Err(err) = read_file() {
+                let message = match err {
+                    GenericError::CustomError(message) => message,
+                    err => err.to_string()
+                };
+                let _ = chunk_sender.send(Err(message));
             }
-            chunk_sender.send(None)?;
-
-            finish_channel.send(FileReaderMessage::ReaderFinished(id))?;
-
             Ok(())
         });
 
-        for _ in 0..MAX_CHUNKS {
-            slot_sender.send(()).unwrap();
-        }
-
         FileReader {
             name,
             chunk_receiver: Mutex::new(chunk_receiver),
             size,
+            offset,
             chunk_sender,
-            slot_sender
+            slot_sender,
+            finished
         }
     }
 
-    pub fn get_chunk(&self) -> Option<FileChunk> {
+    // Whether all of the file has been read, so no new stream needs it.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    pub fn get_chunk(&self) -> Result<Option<FileChunk>, GenericError> {
         let chunk = { 
             let receiver = &*self.chunk_receiver.lock().unwrap();
             receiver.recv().unwrap()
         };
         match chunk {
-            Some(chunk) => {
+            Ok(Some(chunk)) => {
                 let _ = self.slot_sender.send(());
-                Some(chunk)
+                Ok(Some(chunk))
+            }
+            Ok(None) => {
+                self.chunk_sender.send(Ok(None)).unwrap();
+                Ok(None)
             }
-            None => {
-                self.chunk_sender.send(None).unwrap();
-                None
+            Err(err) => {
+                self.chunk_sender.send(Err(err.clone())).unwrap();
+                Err(new_custom_error(&err))
             }
         }
     }
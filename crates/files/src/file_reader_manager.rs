@@ -1,9 +1,9 @@
-use std::{path::PathBuf, sync::{mpsc::{channel, Sender}, Arc}};
+use std::{collections::HashMap, path::PathBuf, sync::{mpsc::{channel, Sender}, Arc}};
 
-use errors::{new_custom_error, GenericError};
+use errors::GenericError;
 use thread_pool::ThreadPool;
 
-use crate::{file_reader::FileReader, list_to_path, FileEntry};
+use crate::{delta::FileSignature, file_reader::{FileReader, ReadPlan}, list_to_path, FileEntry};
 
 pub enum ReaderState {
     Def(FileEntry),
@@ -18,8 +18,7 @@ pub enum ReaderResult {
 }
 
 pub enum FileReaderMessage {
-    GetReader(u32, Sender<ReaderResult>),
-    ReaderFinished(u32)
+    GetReader(u32, u64, Sender<ReaderResult>)
 }
 
 pub struct FileReaderManager {
@@ -30,10 +29,10 @@ impl FileReaderManager {
     pub fn new(
         root: PathBuf,
         files: &Vec<FileEntry>,
+        signatures: HashMap<u32, FileSignature>,
         max_live_readers: u8) -> Self {
 
         let (message_sender, receiver) = channel();
-        let messege_sender_clone = message_sender.clone();
 
         let mut files: Vec<ReaderState> = files.iter().map(|f| {
             ReaderState::Def(f.clone())
@@ -45,42 +44,46 @@ impl FileReaderManager {
         let root_clone = root.clone();
         pool.execute(move || -> Result<(), GenericError> {
             let root = root_clone;
-            let message_sender = messege_sender_clone;
-
-            loop {
-                let mess: FileReaderMessage = receiver.recv()?;
+            let mut signatures = signatures;
 
+            // Ends once the manager is dropped, which also lets go of the
+            // pool, so nothing is left running after a transfer.
+            while let Ok(mess) = receiver.recv() {
                 match mess {
-                    FileReaderMessage::GetReader(id, sender) => {
+                    FileReaderMessage::GetReader(id, offset, sender) => {
                         let state = &files[id as usize];
                         match state {
                             ReaderState::Closed => {
                                 sender.send(ReaderResult::NoReader)?;
                             }
+                            ReaderState::Reader(reader) if reader.is_finished() => {
+                                files[id as usize] = ReaderState::Closed;
+                                sender.send(ReaderResult::NoReader)?;
+                            }
                             ReaderState::Reader(reader) => {
                                 let reader = Arc::clone(&reader);
                                 sender.send(ReaderResult::Instance(reader))?;
                             }
                             ReaderState::Def(f) => {
-                                let message_sender = message_sender.clone();
                                 let file = list_to_path(&f.partial_path);
-                                let name = file.to_str()
-                                    .ok_or(new_custom_error("no file name"))?
-                                    .to_owned();
+                                let name = file.to_string_lossy().into_owned();
                                 let file = root.join(file);
-                                let reader = FileReader::new(id, name, file, f.size, &pool_clone, message_sender);
+                                let signature = signatures.remove(&id);
+                                let plan = ReadPlan {
+                                    offset,
+                                    signature
+                                };
+                                let reader = FileReader::new(name, file, f.size, plan, &pool_clone);
                                 let reader = Arc::new(reader);
                                 files[id as usize] = ReaderState::Reader(Arc::clone(&reader));
                                 sender.send(ReaderResult::FirstInstance(reader))?;
                             }
                         }
                     }
-
-                    FileReaderMessage::ReaderFinished(id) => {
-                        files[id as usize] = ReaderState::Closed;
-                    }
                 }
             }
+
+            Ok(())
         });
 
         FileReaderManager{
@@ -88,9 +91,9 @@ impl FileReaderManager {
         }
     }
 
-    pub fn get_reader(&self, id: u32) -> ReaderResult {
+    pub fn get_reader(&self, id: u32, offset: u64) -> ReaderResult {
         let (sender, receiver) = channel();
-        self.channel.send(FileReaderMessage::GetReader(id, sender)).unwrap(); 
+        self.channel.send(FileReaderMessage::GetReader(id, offset, sender)).unwrap(); 
         let res = receiver.recv().unwrap();
         res
     }
@@ -1,9 +1,38 @@
-use std::{io::Write, path::Path, sync::mpsc::{channel, Sender}};
+use std::{io::{Read, Seek, SeekFrom, Write}, sync::{mpsc::{channel, Sender}, Arc}};
 
 use common::FileStreamMessage;
-use errors::GenericError;
+use errors::{new_custom_error, GenericError};
 use thread_pool::ThreadPool;
-use crate::file_chunk::FileChunk;
+use crate::{decode_name, dest_dir::DestDir, file_chunk::{ChunkSource, FileChunk}, file_metadata::apply_metadata, file_xattrs::apply_xattrs, FileEntry};
+
+#[derive(Clone, Copy)]
+pub enum WriteMode {
+    Create,
+    // Appends to a partial file whose prefix has already been verified.
+    Resume(u64),
+    // Rebuilds the file from delta chunks, copying the referenced
+    // blocks from the existing copy.
+    Delta
+}
+
+// Longest file name most filesystems allow, in bytes.
+static MAX_NAME_LEN: usize = 255;
+
+// The hidden sibling a file is written to before it is renamed into place.
+// The name is fixed, so an interrupted transfer can be resumed from it.
+// Names that would get too long with the extra characters are replaced
+// by their hash.
+pub fn temp_path(partial_path: &[String]) -> Result<Vec<String>, GenericError> {
+    let mut path = partial_path.to_vec();
+    let name = path.last_mut()
+        .ok_or(new_custom_error("no file name"))?;
+    let len = decode_name(name).len();
+    *name = match len + ".".len() + ".ds-part".len() > MAX_NAME_LEN {
+        true => format!(".{}.ds-part", blake3::hash(name.as_bytes()).to_hex()),
+        false => format!(".{}.ds-part", name)
+    };
+    Ok(path)
+}
 
 pub struct FileWriter {
     chunk_sender: Sender<FileChunk>,
@@ -12,25 +41,52 @@ pub struct FileWriter {
 impl FileWriter {
     pub fn new(
         id: u32,
-        size: u64,
-        path: &Path,
+        file: &FileEntry,
+        write_mode: WriteMode,
+        // Write straight into the final file instead of a temporary one.
+        // Delta transfers still need a separate file to copy blocks from.
+        inplace: bool,
+        dest: Arc<DestDir>,
         finish_sender: Sender<FileStreamMessage>,
         pool: ThreadPool) ->
         Result<Self, GenericError> {
 
-        let parent = path.parent();
-        if let Some(parent) = parent {
-            std::fs::create_dir_all(parent)?;
-        }
+        let mut basis = None;
+        let size = file.size;
+        let entry = file.clone();
+        let path = &file.partial_path;
+
+        let rename = match (write_mode, inplace) {
+            (WriteMode::Delta, _) | (_, false) => Some((temp_path(path)?, path.clone())),
+            _ => None
+        };
+        let target = match &rename {
+            Some((tmp, _)) => tmp,
+            None => path
+        };
 
-        let mut writer = std::fs::File::create(path)?;
+        let (mut writer, offset) = match write_mode {
+            WriteMode::Create => (dest.create_file(target)?, 0),
+            WriteMode::Resume(offset) => {
+                let mut file = dest.open_file_write(target)?;
+                file.set_len(offset)?;
+                file.seek(SeekFrom::Start(offset))?;
+                (file, offset)
+            }
+            WriteMode::Delta => {
+                basis = Some(dest.open_file(path)?);
+                (dest.create_file(target)?, 0)
+            }
+        };
 
         let (chunk_sender, chunk_receiver) = channel::<FileChunk>();
-        pool.execute(move || -> Result<(), GenericError> {
-            let mut written = 0;
+        pool.execute_forwarding(finish_sender.clone(), move || -> Result<(), GenericError> {
+            let mut written = offset;
             let mut received = Vec::<FileChunk>::new();
             while written < size {
-                let chunk = chunk_receiver.recv()?;
+                // The streams of the file are gone before all of it arrived.
+                let chunk = chunk_receiver.recv()
+                    .map_err(|_| new_custom_error("connection closed before the file was complete"))?;
                 let index = 'insert_index: {
                     for (i, c) in received.iter().enumerate() {
                         if chunk.offset < c.offset {
@@ -52,10 +108,46 @@ impl FileWriter {
                     }
 
                     let front = received.remove(0);
-                    writer.write(&front.data[..front.size as usize])?;
+                    match front.source {
+                        ChunkSource::Basis(source) => {
+                            let basis = basis.as_mut()
+                                .ok_or(new_custom_error("block reference without a basis file"))?;
+                            basis.seek(SeekFrom::Start(source))?;
+                            let copied = std::io::copy(&mut basis.take(front.size), &mut writer)?;
+                            if copied != front.size {
+                                return Err(new_custom_error("basis file too short"));
+                            }
+                        }
+                        ChunkSource::Hole => {
+                            writer.seek(SeekFrom::Current(front.size as i64))?;
+                        }
+                        ChunkSource::Literal => {
+                            writer.write_all(&front.data[..front.size as usize])?;
+                        }
+                    }
                     written += front.size;
                 }
             }
+            // Seeking over a hole at the end does not extend the file.
+            writer.set_len(size)?;
+
+            // Attributes go on before the mode, which may not allow setting them.
+            if let Some(xattrs) = &entry.xattrs {
+                let target = match &rename {
+                    Some((tmp, _)) => tmp,
+                    None => &entry.partial_path
+                };
+                for failure in dest.with_path(target, |path| apply_xattrs(path, xattrs))? {
+                    finish_sender.send(FileStreamMessage::Warning(id, failure))?;
+                }
+            }
+            apply_metadata(&writer, &entry)?;
+
+            // The data is on disk before the file shows up under its name.
+            if let Some((tmp, path)) = rename {
+                writer.sync_all()?;
+                dest.rename(&tmp, &path)?;
+            }
 
             finish_sender.send(FileStreamMessage::Finish(id))?;
             Ok(())
@@ -72,3 +164,81 @@ impl FileWriter {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::{Seek, SeekFrom, Write}, os::unix::fs::MetadataExt, sync::{mpsc::channel, Arc}};
+
+    use common::FileStreamMessage;
+    use thread_pool::ThreadPool;
+
+    use crate::{dest_dir::DestDir, file_chunk::ChunkSource, file_reader::{FileReader, ReadPlan}, EntryKind, FileEntry};
+
+    use super::{FileWriter, WriteMode};
+
+    #[test]
+    fn holes_are_kept() {
+        let dir = std::env::temp_dir().join(format!("ds-file-writer-{}-holes", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("dest")).unwrap();
+
+        // A hole at the start, one in the middle and one at the end.
+        let mib = 1024 * 1024;
+        let size = 5 * mib;
+        let source = dir.join("src").join("sparse");
+        let mut file = fs::File::create(&source).unwrap();
+        file.set_len(size).unwrap();
+        file.seek(SeekFrom::Start(mib)).unwrap();
+        file.write_all(&[1; 64 * 1024]).unwrap();
+        file.seek(SeekFrom::Start(3 * mib)).unwrap();
+        file.write_all(&[2; 64 * 1024]).unwrap();
+        drop(file);
+        let sparse = fs::metadata(&source).unwrap().blocks() * 512 < size;
+
+        let pool = ThreadPool::new(2);
+        let entry = FileEntry {
+            partial_path: vec!["sparse".to_owned()],
+            kind: EntryKind::File,
+            size,
+            mode: 0o644,
+            mtime: 0,
+            atime: 0,
+            xattrs: None
+        };
+        let plan = ReadPlan {
+            offset: 0,
+            signature: None
+        };
+        let reader = FileReader::new("sparse".to_owned(), source.clone(), size, plan, &pool);
+        let dest = Arc::new(DestDir::open(&dir.join("dest")).unwrap());
+        let (sender, receiver) = channel();
+        let writer = FileWriter::new(0, &entry, WriteMode::Create, false, dest, sender, pool.clone()).unwrap();
+
+        let mut holes = 0;
+        while let Some(chunk) = reader.get_chunk().unwrap() {
+            if chunk.source == ChunkSource::Hole {
+                holes += 1;
+            }
+            writer.push_chunk(chunk).unwrap();
+        }
+        loop {
+            match receiver.recv().unwrap() {
+                FileStreamMessage::Finish(_) => break,
+                FileStreamMessage::Failed(err) => panic!("{}", err),
+                _ => { }
+            }
+        }
+
+        let received = dir.join("dest").join("sparse");
+        assert_eq!(fs::metadata(&received).unwrap().len(), size);
+        assert!(fs::read(&received).unwrap() == fs::read(&source).unwrap());
+        // Filesystems without hole support report the whole file as data.
+        if sparse {
+            assert_eq!(holes, 3);
+            assert!(fs::metadata(&received).unwrap().blocks() * 512 < size);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use crate::path_encoding::{decode_name, encode_name};
+
+// Extended attributes are read and written without following symbolic
+// links. POSIX ACLs are stored by Linux as the `system.posix_acl_access`
+// and `system.posix_acl_default` attributes, so they travel the same way.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExtendedAttribute {
+    // Encoded like file names, attribute names are bytes as well.
+    pub name: String,
+    pub value: Vec<u8>
+}
+
+// Attributes that can't be read are left out.
+pub fn read_xattrs(path: &Path) -> Vec<ExtendedAttribute> {
+    let mut attrs = vec![];
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(_) => {
+            return attrs;
+        }
+    };
+
+    for name in names {
+        if let Ok(Some(value)) = xattr::get(path, &name) {
+            attrs.push(ExtendedAttribute {
+                name: encode_name(&name),
+                value
+            });
+        }
+    }
+
+    attrs
+}
+
+// Security labels that the sender does not have are kept, since they
+// usually come from local policy.
+fn is_kept(attr: &ExtendedAttribute, attrs: &[ExtendedAttribute]) -> bool {
+    attr.name.starts_with("security.") || attrs.iter().any(|a| a.name == attr.name)
+}
+
+// Whether `apply_xattrs` would leave attributes that are now `current` as they are.
+pub fn xattrs_match(current: &[ExtendedAttribute], attrs: &[ExtendedAttribute]) -> bool {
+    attrs.iter().all(|a| current.contains(a)) && current.iter().all(|c| is_kept(c, attrs))
+}
+
+// Makes the attributes of `path` match `attrs`.
+// Returns a description of every attribute that could not be applied.
+pub fn apply_xattrs(path: &Path, attrs: &[ExtendedAttribute]) -> Vec<String> {
+    let mut failures = vec![];
+
+    let current = read_xattrs(path);
+    for attr in current.iter() {
+        if is_kept(attr, attrs) {
+            continue;
+        }
+        if let Err(e) = xattr::remove(path, decode_name(&attr.name)) {
+            failures.push(format!("{}: could not remove {}: {}", path.display(), attr.name, e));
+        }
+    }
+
+    for attr in attrs.iter() {
+        if current.contains(attr) {
+            continue;
+        }
+        if let Err(e) = xattr::set(path, decode_name(&attr.name), &attr.value) {
+            failures.push(format!("{}: could not set {}: {}", path.display(), attr.name, e));
+        }
+    }
+
+    failures
+}
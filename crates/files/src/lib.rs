@@ -1,16 +1,33 @@
+mod delta;
+mod dest_dir;
 mod dir_scanner;
+mod dir_watcher;
 mod file_chunk;
+mod file_hash;
+mod file_metadata;
 mod file_reader;
 mod file_reader_manager;
 mod file_writer;
+mod file_xattrs;
+mod path_encoding;
+mod scan_filter;
 
-pub use dir_scanner::get_files_in_dir;
-pub use dir_scanner::FileEntry;
+pub use dir_scanner::{get_files_in_dir, scan_source, source_root};
+pub use dir_scanner::{EntryKind, FileEntry};
 pub use dir_scanner::path_to_list;
 pub use dir_scanner::list_to_path;
+pub use path_encoding::{check_partial_path, decode_name, encode_name};
+pub use dir_scanner::{to_timestamp, from_timestamp};
+pub use scan_filter::{select_files, LinkMode, ScanFilter, ScanOptions};
 
 pub use file_reader_manager::ReaderResult;
 pub use file_reader_manager::FileReaderManager;
 pub use file_reader_manager::ReaderState;
-pub use file_chunk::{FileChunk, FILE_CHUNK_MAX_SIZE};
-pub use file_writer::FileWriter;
+pub use file_chunk::{FileChunk, FILE_CHUNK_HEADER_SIZE, FILE_CHUNK_MAX_SIZE};
+pub use file_writer::{temp_path, FileWriter, WriteMode};
+pub use dest_dir::{exchange_dirs, DestDir};
+pub use dir_watcher::DirWatcher;
+pub use file_hash::hash_file_prefix;
+pub use file_metadata::apply_metadata;
+pub use file_xattrs::{apply_xattrs, read_xattrs, xattrs_match, ExtendedAttribute};
+pub use delta::{compute_signature, FileSignature, DELTA_MIN_SIZE};
@@ -0,0 +1,153 @@
+use std::{ffi::{OsStr, OsString}, os::unix::ffi::{OsStrExt, OsStringExt}};
+
+use errors::{new_custom_error, GenericError};
+
+// File names are plain bytes on Linux, but they travel in JSON strings.
+// Valid UTF-8 is kept as it is, every other byte is written as `\xHH`,
+// and a backslash is doubled, so any name comes back unchanged.
+pub fn encode_name(name: &OsStr) -> String {
+    let mut res = String::new();
+
+    for chunk in name.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '\\' {
+                res.push_str("\\\\");
+            }
+            else {
+                res.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            res.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+
+    res
+}
+
+// Names that are not valid escapes are taken literally.
+pub fn decode_name(name: &str) -> OsString {
+    let bytes = name.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            if bytes.get(i + 1) == Some(&b'\\') {
+                res.push(b'\\');
+                i += 2;
+                continue;
+            }
+
+            let escaped = match (bytes.get(i + 1), bytes.get(i + 2..i + 4)) {
+                (Some(b'x'), Some(hex)) => std::str::from_utf8(hex).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                _ => None
+            };
+            if let Some(b) = escaped {
+                res.push(b);
+                i += 4;
+                continue;
+            }
+        }
+
+        res.push(bytes[i]);
+        i += 1;
+    }
+
+    OsString::from_vec(res)
+}
+
+// A path received from the peer has to stay below the directory it is
+// joined to, so every component must be a plain, non-empty name.
+pub fn check_partial_path(partial_path: &[String]) -> Result<(), GenericError> {
+    if partial_path.is_empty() {
+        return Err(new_custom_error("protocol error: empty path in the file list"));
+    }
+
+    for name in partial_path.iter() {
+        let name = decode_name(name);
+        let bytes = name.as_bytes();
+        if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') || bytes.contains(&0) {
+            return Err(new_custom_error(&format!(
+                "protocol error: invalid path component {:?} in {}",
+                name,
+                partial_path.join("/"))));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    use super::{check_partial_path, decode_name, encode_name};
+
+    fn round_trip(bytes: &[u8]) {
+        let name = OsStr::from_bytes(bytes);
+        assert_eq!(decode_name(&encode_name(name)), name);
+    }
+
+    #[test]
+    fn utf8_names_are_kept() {
+        assert_eq!(encode_name(OsStr::new("dir_sync ü.txt")), "dir_sync ü.txt");
+        round_trip("dir_sync ü.txt".as_bytes());
+    }
+
+    #[test]
+    fn invalid_utf8_is_escaped() {
+        assert_eq!(encode_name(OsStr::from_bytes(b"a\xffb")), "a\\xffb");
+        round_trip(b"a\xffb");
+        round_trip(b"\x80\x81");
+        round_trip(b"\xe2\x82");
+    }
+
+    #[test]
+    fn backslashes_are_doubled() {
+        assert_eq!(encode_name(OsStr::new("a\\b")), "a\\\\b");
+        round_trip(b"a\\b");
+        round_trip(b"\\x41");
+        round_trip(b"\\\\");
+        round_trip(b"end\\");
+    }
+
+    fn path(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn plain_paths_are_accepted() {
+        assert!(check_partial_path(&path(&["a"])).is_ok());
+        assert!(check_partial_path(&path(&["a", "b", ".hidden", "..."])).is_ok());
+    }
+
+    #[test]
+    fn paths_leaving_the_root_are_rejected() {
+        assert!(check_partial_path(&path(&[])).is_err());
+        assert!(check_partial_path(&path(&[".."])).is_err());
+        assert!(check_partial_path(&path(&["a", "..", ".."])).is_err());
+        assert!(check_partial_path(&path(&["."])).is_err());
+        assert!(check_partial_path(&path(&["a", ""])).is_err());
+        assert!(check_partial_path(&path(&["a/b"])).is_err());
+        assert!(check_partial_path(&path(&["a\u{0}b"])).is_err());
+    }
+
+    #[test]
+    fn escaped_components_are_checked_decoded() {
+        assert!(check_partial_path(&path(&["\\x2f"])).is_err());
+        assert!(check_partial_path(&path(&["..\\x2f..\\x2fetc"])).is_err());
+        assert!(check_partial_path(&path(&["\\x2e\\x2e"])).is_err());
+        assert!(check_partial_path(&path(&["a\\x00b"])).is_err());
+        // An escaped backslash followed by `x2f` is just those characters.
+        assert!(check_partial_path(&path(&["\\\\x2f"])).is_ok());
+    }
+
+    #[test]
+    fn invalid_escapes_are_literal() {
+        assert_eq!(decode_name("a\\xzz"), OsStr::new("a\\xzz"));
+        assert_eq!(decode_name("a\\x4"), OsStr::new("a\\x4"));
+        assert_eq!(decode_name("a\\"), OsStr::new("a\\"));
+    }
+}
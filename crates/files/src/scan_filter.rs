@@ -0,0 +1,296 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::Arc};
+
+use errors::{new_custom_error, GenericError};
+use ignore::{gitignore::{self, Gitignore, GitignoreBuilder}, Match};
+
+use crate::{list_to_path, EntryKind, FileEntry};
+
+pub static IGNORE_FILE_NAME: &str = ".dsignore";
+
+static GIT_DIR_NAME: &str = ".git";
+static GITIGNORE_FILE_NAME: &str = ".gitignore";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum LinkMode {
+    // Symbolic links are sent as links and recreated on the receiver.
+    #[default]
+    Preserve,
+    // Symbolic links are replaced by what they point to.
+    Follow,
+    Skip
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub gitignore: bool,
+    pub links: LinkMode,
+    pub xattrs: bool,
+}
+
+// The ignore rules in effect for one directory of the scan. Rules given
+// on the command line win over ignore files, and the ignore file of a
+// deeper directory wins over the ones above it. Git's own rules come
+// last, in the order git applies them: `.gitignore` files, then
+// `.git/info/exclude`, then the global excludes file.
+#[derive(Clone)]
+pub struct ScanFilter {
+    command_line: Arc<Gitignore>,
+    ignore_files: Vec<Arc<Gitignore>>,
+    gitignore: bool,
+    git_rules: Vec<Arc<Gitignore>>
+}
+
+// Errors of the ignore crate only make it out as their message.
+fn ignore_error(err: ignore::Error) -> GenericError {
+    new_custom_error(&err.to_string())
+}
+
+fn check_add(err: Option<ignore::Error>) -> Result<(), GenericError> {
+    match err {
+        Some(err) => Err(ignore_error(err)),
+        None => Ok(())
+    }
+}
+
+impl ScanFilter {
+    pub fn new(root: &Path, options: &ScanOptions) -> Result<Self, GenericError> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in options.exclude.iter() {
+            builder.add_line(None, pattern).map_err(ignore_error)?;
+        }
+        for pattern in options.include.iter() {
+            builder.add_line(None, &format!("!{}", pattern)).map_err(ignore_error)?;
+        }
+
+        let mut filter = ScanFilter {
+            command_line: Arc::new(builder.build().map_err(ignore_error)?),
+            ignore_files: vec![],
+            gitignore: options.gitignore,
+            git_rules: vec![]
+        };
+
+        // When the scan starts inside a repository, the rules of the
+        // directories above the scan root apply as well.
+        if options.gitignore {
+            let repo = root.ancestors()
+                .skip(1)
+                .find(|dir| dir.join(GIT_DIR_NAME).exists());
+
+            if let Some(repo) = repo {
+                filter.git_rules = repo_rules(repo)?;
+
+                let dirs: Vec<&Path> = root.ancestors()
+                    .skip(1)
+                    .take_while(|dir| *dir != repo)
+                    .collect();
+                filter.add_rules_file(repo, GITIGNORE_FILE_NAME)?;
+                for dir in dirs.iter().rev() {
+                    filter.add_rules_file(dir, GITIGNORE_FILE_NAME)?;
+                }
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn add_rules_file(&mut self, dir: &Path, name: &str) -> Result<(), GenericError> {
+        let file = dir.join(name);
+        if !file.is_file() {
+            return Ok(());
+        }
+
+        let mut builder = GitignoreBuilder::new(dir);
+        check_add(builder.add(file))?;
+        let rules = Arc::new(builder.build().map_err(ignore_error)?);
+        match name {
+            x if x == IGNORE_FILE_NAME => self.ignore_files.push(rules),
+            _ => self.git_rules.push(rules)
+        }
+
+        Ok(())
+    }
+
+    // Returns the filter for the contents of `dir`, picking up its ignore files if it has any.
+    pub fn enter_dir(&self, dir: &Path) -> Result<Self, GenericError> {
+        let mut filter = self.clone();
+
+        filter.add_rules_file(dir, IGNORE_FILE_NAME)?;
+
+        if self.gitignore {
+            // A nested repository does not inherit the rules of the one around it.
+            if dir.join(GIT_DIR_NAME).exists() {
+                filter.git_rules = repo_rules(dir)?;
+            }
+            filter.add_rules_file(dir, GITIGNORE_FILE_NAME)?;
+        }
+
+        Ok(filter)
+    }
+
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self.gitignore && path.file_name() == Some(GIT_DIR_NAME.as_ref()) {
+            return true;
+        }
+
+        let rules = std::iter::once(&self.command_line)
+            .chain(self.ignore_files.iter().rev())
+            .chain(self.git_rules.iter().rev());
+
+        for rule in rules {
+            match rule.matched(path, is_dir) {
+                Match::None => { }
+                Match::Ignore(_) => {
+                    return true;
+                }
+                Match::Whitelist(_) => {
+                    return false;
+                }
+            }
+        }
+
+        false
+    }
+}
+
+// The repository wide rules of the repository at `repo`, lowest precedence first.
+fn repo_rules(repo: &Path) -> Result<Vec<Arc<Gitignore>>, GenericError> {
+    let mut files: Vec<PathBuf> = vec![];
+    if let Some(global) = gitignore::gitconfig_excludes_path() {
+        files.push(global);
+    }
+    files.push(repo.join(GIT_DIR_NAME).join("info").join("exclude"));
+
+    let mut rules = vec![];
+    for file in files {
+        if !file.is_file() {
+            continue;
+        }
+
+        let mut builder = GitignoreBuilder::new(repo);
+        check_add(builder.add(file))?;
+        rules.push(Arc::new(builder.build().map_err(ignore_error)?));
+    }
+
+    Ok(rules)
+}
+
+// The entries of a scan of `root` that match one of `patterns`, which use
+// the same syntax as `--include`. A matching directory brings everything
+// below it, and the directories above a match are kept as well.
+pub fn select_files(root: &Path, files: &[FileEntry], patterns: &[String]) -> Result<Vec<FileEntry>, GenericError> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns.iter() {
+        builder.add_line(None, pattern).map_err(ignore_error)?;
+    }
+    let matcher = builder.build().map_err(ignore_error)?;
+
+    let mut selected = HashSet::<&Vec<String>>::new();
+    let mut parents = HashSet::<&[String]>::new();
+    for f in files.iter() {
+        let path = root.join(list_to_path(&f.partial_path));
+        if matcher.matched_path_or_any_parents(&path, f.kind == EntryKind::Dir).is_ignore() {
+            selected.insert(&f.partial_path);
+            for i in 1..f.partial_path.len() {
+                parents.insert(&f.partial_path[..i]);
+            }
+        }
+    }
+
+    // Another name of a file that was left out carries the data itself.
+    let sizes: HashMap<&Vec<String>, u64> = files.iter()
+        .filter(|f| f.kind == EntryKind::File)
+        .map(|f| (&f.partial_path, f.size))
+        .collect();
+
+    let mut res = vec![];
+    for f in files.iter() {
+        if !selected.contains(&f.partial_path) && !parents.contains(f.partial_path.as_slice()) {
+            continue;
+        }
+
+        let mut f = f.clone();
+        match &f.kind {
+            EntryKind::HardLink(target) if !selected.contains(target) => {
+                f.size = sizes.get(target).copied().unwrap_or(0);
+                f.kind = EntryKind::File;
+            }
+            _ => { }
+        }
+        res.push(f);
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{EntryKind, FileEntry};
+
+    use super::select_files;
+
+    fn entry(path: &str, kind: EntryKind, size: u64) -> FileEntry {
+        FileEntry {
+            partial_path: path.split('/').map(|n| n.to_string()).collect(),
+            kind,
+            size,
+            mode: 0o644,
+            mtime: 0,
+            atime: 0,
+            xattrs: None
+        }
+    }
+
+    fn select(files: &[FileEntry], patterns: &[&str]) -> Vec<String> {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        select_files(Path::new("/root"), files, &patterns).unwrap().iter()
+            .map(|f| f.partial_path.join("/"))
+            .collect()
+    }
+
+    fn tree() -> Vec<FileEntry> {
+        vec![
+            entry("a", EntryKind::Dir, 0),
+            entry("a/b", EntryKind::Dir, 0),
+            entry("a/b/x.txt", EntryKind::File, 1),
+            entry("a/b/y.bin", EntryKind::File, 1),
+            entry("a/z.bin", EntryKind::File, 1),
+            entry("c", EntryKind::Dir, 0),
+            entry("top.txt", EntryKind::File, 1)
+        ]
+    }
+
+    #[test]
+    fn parents_of_a_match_are_kept() {
+        assert_eq!(select(&tree(), &["x.txt"]), ["a", "a/b", "a/b/x.txt"]);
+        assert_eq!(select(&tree(), &["*.txt"]), ["a", "a/b", "a/b/x.txt", "top.txt"]);
+        assert!(select(&tree(), &["nothing"]).is_empty());
+    }
+
+    #[test]
+    fn matching_dirs_bring_their_contents() {
+        assert_eq!(select(&tree(), &["/a/b"]), ["a", "a/b", "a/b/x.txt", "a/b/y.bin"]);
+        assert_eq!(select(&tree(), &["/a/b", "/c"]), ["a", "a/b", "a/b/x.txt", "a/b/y.bin", "c"]);
+    }
+
+    #[test]
+    fn links_to_files_left_out_carry_the_data() {
+        let files = vec![
+            entry("data", EntryKind::File, 100),
+            entry("link", EntryKind::HardLink(vec!["data".to_string()]), 0)
+        ];
+
+        let selected = select_files(Path::new("/root"), &files, &["/link".to_string()]).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].kind, EntryKind::File);
+        assert_eq!(selected[0].size, 100);
+
+        // With the target picked as well, the link stays a link.
+        let selected = select_files(Path::new("/root"), &files, &["/link".to_string(), "/data".to_string()]).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[1].kind, EntryKind::HardLink(vec!["data".to_string()]));
+    }
+}
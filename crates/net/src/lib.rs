@@ -1,4 +1,4 @@
-use std::{io::{Read, Write}, net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream}};
+use std::{collections::HashMap, io::{Read, Write}, net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream}, sync::{mpsc::{channel, Receiver, Sender}, Arc, Mutex}, time::Duration};
 
 use errors::{new_custom_error, GenericError};
 use serde_json::Value;
@@ -13,26 +13,51 @@ struct TcpMessage {
     payload: TcpMessagePayload
 }
 
+// How long a new connection has to say what it is for.
+static HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub trait TcpEndpoint : Send + 'static {
     fn get_connection(&mut self) -> Result<TcpStream, GenericError>;
     fn wait_for_connection(&mut self) -> Result<TcpStream, GenericError>;
 }
 
-struct TcpClientEnd(SocketAddr, JSONReader);
+// The first bytes of every connection tell the server what it is for:
+// a new session, or a data connection of the session with the given id.
+static HELLO_SIZE: usize = 9;
+static NEW_SESSION: u8 = 0;
+static JOIN_SESSION: u8 = 1;
+
+fn hello(kind: u8, session: u64) -> Vec<u8> {
+    let mut buf = vec![0; HELLO_SIZE];
+    buf[0] = kind;
+    buf[1..].copy_from_slice(&session.to_le_bytes());
+    buf
+}
+
+struct TcpClientEnd(SocketAddr, JSONReader, u64);
 
 impl TcpClientEnd {
     fn new(addr: SocketAddr) -> Result<Self, GenericError> {
-        let main_stream = TcpStream::connect(addr)?;
+        let mut main_stream = TcpStream::connect(addr)?;
+        main_stream.write_all(&hello(NEW_SESSION, 0))?;
+
+        let mut session = [0; 8];
+        main_stream.read_exact(&mut session)?;
+
         let reader = JSONReader::new(main_stream);
-        Ok(TcpClientEnd(addr, reader))
+        Ok(TcpClientEnd(addr, reader, u64::from_le_bytes(session)))
+    }
+
+    fn connect(&self) -> Result<TcpStream, GenericError> {
+        let mut stream = TcpStream::connect(self.0)?;
+        stream.write_all(&hello(JOIN_SESSION, self.2))?;
+        Ok(stream)
     }
 }
 
 impl TcpEndpoint for TcpClientEnd {
     fn get_connection(&mut self) -> Result<TcpStream, GenericError> {
-        let addr = &self.0;
-        let stream = TcpStream::connect(addr)?;
-        Ok(stream)
+        self.connect()
     }
 
     fn wait_for_connection(&mut self) -> Result<TcpStream, GenericError> {
@@ -43,21 +68,21 @@ impl TcpEndpoint for TcpClientEnd {
 
         match message.payload {
             TcpMessagePayload::NewConnection => {
-                let stream = TcpStream::connect(self.0)?;
-                Ok(stream)
+                self.connect()
             }
         }
     }
 }
 
-struct TcpServerEnd {
-    listener: TcpListener,
-    addr: SocketAddr,
-    main_stream: Option<TcpStream>
+type SessionMap = Arc<Mutex<HashMap<u64, Sender<TcpStream>>>>;
+
+// Accepts clients, each of which gets a session of its own.
+pub struct TcpServer {
+    sessions: Receiver<TcpServerSession>
 }
 
-impl TcpServerEnd {
-    fn new(port: Option<u16>) -> Result<Self, GenericError> {
+impl TcpServer {
+    fn new(port: Option<u16>) -> Result<(Self, SocketAddr), GenericError> {
         let ip = Ipv4Addr::new(0, 0, 0, 0);
         let ip = IpAddr::V4(ip);
         let port = match port {
@@ -69,68 +94,175 @@ impl TcpServerEnd {
         let listener = std::net::TcpListener::bind(addr)?;
         let addr = listener.local_addr()?;
 
-        let server_end = TcpServerEnd {
-            listener,
-            addr,
-            main_stream: None
+        let (session_sender, session_receiver) = channel();
+        let sessions = SessionMap::default();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => {
+                        continue;
+                    }
+                };
+
+                // A slow client can't hold up the others.
+                let session_sender = session_sender.clone();
+                let sessions = Arc::clone(&sessions);
+                std::thread::spawn(move || {
+                    let _ = route_connection(stream, &sessions, &session_sender);
+                });
+            }
+        });
+
+        let server = TcpServer {
+            sessions: session_receiver
         };
-        Ok(server_end)
+        Ok((server, addr))
     }
 
-    fn get_addr(&self) -> SocketAddr {
-        self.addr
+    // Blocks until the next client connects.
+    pub fn accept_session(&self) -> Result<TcpServerSession, GenericError> {
+        Ok(self.sessions.recv()?)
     }
+}
 
-    fn init_main_stream(&mut self) -> Result<(), GenericError> {
-        if let Some(_) = self.main_stream {
-            return Ok(());
+// Session ids are random, so a client can't guess its way into another
+// client's session.
+fn new_session_id(sessions: &HashMap<u64, Sender<TcpStream>>) -> Result<u64, GenericError> {
+    let mut random = std::fs::File::open("/dev/urandom")?;
+    loop {
+        let mut buf = [0; 8];
+        random.read_exact(&mut buf)?;
+        let id = u64::from_le_bytes(buf);
+        if !sessions.contains_key(&id) {
+            return Ok(id);
         }
-        let (stream, _) = self.listener.accept()?;
-        self.main_stream = Some(stream);
+    }
+}
 
-        Ok(())
+fn route_connection(
+    mut stream: TcpStream,
+    sessions: &SessionMap,
+    session_sender: &Sender<TcpServerSession>) -> Result<(), GenericError> {
+
+    let mut buf = vec![0; HELLO_SIZE];
+    stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    stream.read_exact(&mut buf)?;
+    stream.set_read_timeout(None)?;
+
+    let mut id = [0; 8];
+    id.copy_from_slice(&buf[1..]);
+    let id = u64::from_le_bytes(id);
+    match buf[0] {
+        kind if kind == NEW_SESSION => {
+            let (sender, receiver) = channel();
+            let id = {
+                let sessions = &mut *sessions.lock().unwrap();
+                let id = new_session_id(sessions)?;
+                sessions.insert(id, sender);
+                id
+            };
+            stream.write_all(&id.to_le_bytes())?;
+
+            session_sender.send(TcpServerSession {
+                id,
+                main_stream: stream,
+                connections: receiver,
+                sessions: Arc::clone(sessions)
+            })?;
+        }
+        kind if kind == JOIN_SESSION => {
+            let sender = sessions.lock().unwrap().get(&id).cloned();
+            match sender {
+                Some(sender) => sender.send(stream)?,
+                None => {
+                    return Err(new_custom_error("unknown session"));
+                }
+            }
+        }
+        _ => {
+            return Err(new_custom_error("unknown connection type"));
+        }
     }
+
+    Ok(())
 }
 
-impl TcpEndpoint for TcpServerEnd {
+// One client of the server, with its own control stream and data connections.
+pub struct TcpServerSession {
+    id: u64,
+    main_stream: TcpStream,
+    connections: Receiver<TcpStream>,
+    sessions: SessionMap
+}
+
+impl TcpServerSession {
+    pub fn peer_addr(&self) -> Result<SocketAddr, GenericError> {
+        Ok(self.main_stream.peer_addr()?)
+    }
+
+    // A handle that ends the session from outside, wherever it is blocked.
+    pub fn closer(&self) -> Result<SessionCloser, GenericError> {
+        Ok(SessionCloser {
+            id: self.id,
+            main_stream: self.main_stream.try_clone()?,
+            sessions: Arc::clone(&self.sessions)
+        })
+    }
+}
+
+pub struct SessionCloser {
+    id: u64,
+    main_stream: TcpStream,
+    sessions: SessionMap
+}
+
+impl SessionCloser {
+    // Waiting for a data connection fails once the session is out of the
+    // map, and reading the control stream fails once it is shut down. The
+    // client then gives up too, which ends its data connections.
+    pub fn close(&self) {
+        self.sessions.lock().unwrap().remove(&self.id);
+        let _ = self.main_stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+impl Drop for TcpServerSession {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TcpEndpoint for TcpServerSession {
     fn get_connection(&mut self) -> Result<TcpStream, GenericError> {
-        self.init_main_stream()?;
-
-        match &mut self.main_stream {
-            None => Err(errors::new_custom_error("No main connection!")),
-            Some(stream) => {
-                let payload = TcpMessagePayload::NewConnection;
-                let message = TcpMessage {
-                    payload
-                };
-                let message = serde_json::to_string(&message)?;
+        let payload = TcpMessagePayload::NewConnection;
+        let message = TcpMessage {
+            payload
+        };
+        let message = serde_json::to_string(&message)?;
 
-                stream.write(message.as_bytes())?;
+        self.main_stream.write_all(message.as_bytes())?;
 
-                let (stream, _) = self.listener.accept()?;
-                Ok(stream)
-            }
-        }
+        Ok(self.connections.recv()?)
     }
 
     fn wait_for_connection(&mut self) -> Result<TcpStream, GenericError> {
-        self.init_main_stream()?;
-
-        let (stream, _) = self.listener.accept()?;
-        Ok(stream)
+        Ok(self.connections.recv()?)
     }
 }
 
+// Works on raw bytes, so that a multi-byte character split between two
+// reads stays intact, and skips braces that are part of string values.
 pub struct JSONReader {
     stream: TcpStream,
-    read: String,
+    read: Vec<u8>,
 }
 
 impl JSONReader {
     pub fn new(stream: TcpStream) -> Self {
         JSONReader {
             stream,
-            read: "".into()
+            read: vec![]
         }
     }
 
@@ -138,8 +270,10 @@ impl JSONReader {
         let mut buff: Vec<u8> = Vec::<u8>::with_capacity(256);
         buff.resize(256, 0);
 
-        let mut parsed: String = "".into();
+        let mut parsed: Vec<u8> = vec![];
         let mut brackets: u32 = 0;
+        let mut in_string = false;
+        let mut escaped = false;
 
         let read = &mut self.read;
 
@@ -152,17 +286,29 @@ impl JSONReader {
                     return Err(new_custom_error("stream closed"));
                 }
 
-                let read_str = &*String::from_utf8_lossy(&buff[..bytes_read]);
-                read.push_str(read_str);
+                read.extend_from_slice(&buff[..bytes_read]);
             }
 
             let num_parsed = 'parsed_chars: {
-                for (index, c) in read.char_indices() {
-                    match c {
-                        '{' => {
+                for (index, c) in read.iter().enumerate() {
+                    match (in_string, escaped, c) {
+                        (true, true, _) => {
+                            escaped = false;
+                        }
+                        (true, false, b'\\') => {
+                            escaped = true;
+                        }
+                        (true, false, b'"') => {
+                            in_string = false;
+                        }
+                        (true, false, _) => {}
+                        (false, _, b'"') => {
+                            in_string = true;
+                        }
+                        (false, _, b'{') => {
                             brackets += 1;
                         }
-                        '}' => {
+                        (false, _, b'}') => {
                             brackets -= 1;
                         }
                         _ => {}
@@ -178,22 +324,19 @@ impl JSONReader {
                 None => (read.len(), false)
             };
 
-            parsed.push_str(&read[..prefix]);
-            *read = read[prefix..].into();
+            parsed.extend_from_slice(&read[..prefix]);
+            read.drain(..prefix);
 
             if full_json {
-                let json: serde_json::Value = serde_json::from_str(&parsed)?;
+                let json: serde_json::Value = serde_json::from_slice(&parsed)?;
                 return Ok(json);
             }
         }
     }
 }
 
-pub fn new_server_endpoint(port: Option<u16>) -> Result<(impl TcpEndpoint, SocketAddr), GenericError> {
-    let server = TcpServerEnd::new(port)?;
-    let addr = server.get_addr();
-
-    Ok((server, addr))
+pub fn new_server(port: Option<u16>) -> Result<(TcpServer, SocketAddr), GenericError> {
+    TcpServer::new(port)
 }
 
 pub fn new_client_endpoint(addr: SocketAddr) -> Result<impl TcpEndpoint, GenericError> {
@@ -1,4 +1,4 @@
-use std::sync::{mpsc::{channel, Receiver, Sender}, Arc, LazyLock, Mutex};
+use std::{cell::RefCell, sync::{mpsc::{channel, Receiver, Sender}, Arc, LazyLock, Mutex}};
 
 use errors::GenericError;
 
@@ -19,8 +19,19 @@ pub fn get_report_receiver() -> ReportReceiver {
     Arc::clone(&REPORT_CHANNEL.1)
 }
 
+thread_local! {
+    static THREAD_REPORT_CHANNEL: RefCell<Option<ReportSender>> = const { RefCell::new(None) };
+}
+
+// The channel errors are reported to: the one of the pool the current
+// thread belongs to, or the global one.
 pub fn get_report_channel() -> ReportSender {
-    REPORT_CHANNEL.0.clone()
+    THREAD_REPORT_CHANNEL.with_borrow(|channel| {
+        match channel {
+            Some(channel) => channel.clone(),
+            None => REPORT_CHANNEL.0.clone()
+        }
+    })
 }
 
 #[derive(Clone)]
@@ -28,13 +39,35 @@ pub struct ThreadPool(Arc<ThreadPoolInternal>);
 
 impl ThreadPool {
     pub fn new(num_threads: u8) -> Self {
-        let pool = ThreadPoolInternal::new(num_threads);
+        Self::with_report_channel(num_threads, get_report_channel())
+    }
+
+    // Errors of the tasks go to `report_channel`, and so do the errors of
+    // pools that are created by the tasks, so a whole group of work can be
+    // failed without stopping everything else.
+    pub fn with_report_channel(num_threads: u8, report_channel: ReportSender) -> Self {
+        let pool = ThreadPoolInternal::new(num_threads, report_channel);
         ThreadPool(Arc::new(pool))
     }
 
     pub fn execute(&self, task: impl FnOnce() -> Result<(), GenericError> + Send + 'static) {
         self.0.execute(task)
     }
+
+    // Runs `task`, but sends its error to `sender` instead of the report
+    // channel. The code that waits on the task would otherwise keep waiting
+    // for it forever. Once the receiver is gone there is nobody left to tell.
+    pub fn execute_forwarding<M: From<GenericError> + Send + 'static>(
+        &self,
+        sender: Sender<M>,
+        task: impl FnOnce() -> Result<(), GenericError> + Send + 'static) {
+        self.execute(move || -> Result<(), GenericError> {
+            if let Err(err) = task() {
+                let _ = sender.send(M::from(err));
+            }
+            Ok(())
+        })
+    }
 }
 
 struct ThreadPoolInternal {
@@ -43,30 +76,37 @@ struct ThreadPoolInternal {
 }
 
 impl ThreadPoolInternal {
-    fn new(num_threads: u8) -> Self {
+    fn new(num_threads: u8, report_channel: ReportSender) -> Self {
         let (task_sender, task_receiver) = channel::<Option<Box<TaskPtr>>>();
         let task_receiver = Arc::new(Mutex::new(task_receiver));
         for _ in 0..num_threads {
             let receiver = Arc::clone(&task_receiver);
-            let report_channel = get_report_channel();
-
-            std::thread::spawn(move || loop {
-                let task = {
-                    let receiver = &mut *receiver.lock().unwrap();
-                    receiver.recv().unwrap()
-                };
-
-                if let Some(task) = task {
-                    let res = task();
-                    match res {
-                        Err(err) => {
-                            report_channel.send(Some(err)).unwrap();
+            let report_channel = report_channel.clone();
+
+            std::thread::spawn(move || {
+                // Pools created by the tasks report to the same channel.
+                THREAD_REPORT_CHANNEL.set(Some(report_channel.clone()));
+
+                loop {
+                    let task = {
+                        let receiver = &mut *receiver.lock().unwrap();
+                        receiver.recv().unwrap()
+                    };
+
+                    if let Some(task) = task {
+                        let res = task();
+                        match res {
+                            // Only the first error of a group is waited for, the
+                            // others may have nobody to go to.
+                            Err(err) => {
+                                let _ = report_channel.send(Some(err));
+                            }
+                            _ => {}
                         }
-                        _ => {}
                     }
-                }
-                else {
-                    break;
+                    else {
+                        break;
+                    }
                 }
             });
 